@@ -1,11 +1,25 @@
-use clap::{ArgAction, Parser};
+use std::ffi::OsString;
+use std::fs;
+use std::path::PathBuf;
+
+use clap::parser::ValueSource;
+use clap::{ArgAction, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use toml_edit::{DocumentMut, Item, Table, Value};
+
+use crate::func;
+use crate::sign::{SignFormat, SignOptions};
+use crate::structs::{BranchPick, Limits};
 
 /// Mass git repository search, replace and commit tool
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 pub struct Config {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     /// Path to repositories
     #[arg(
+        global = true,
         short = 'p',
         long = "path",
         value_name = "PATH",
@@ -14,26 +28,198 @@ pub struct Config {
     )]
     pub path: String,
 
-    /// Specify Regex pattern for branches to checkout
+    /// Use settings of `[profile.NAME]` from configuration files.
+    /// Settings are keyed by long flag names, i.e. `path`, `branch`, `remote`, `author` and `push`
+    #[arg(
+        global = true,
+        long = "profile",
+        value_name = "NAME",
+        env = "GITRAIDER_PROFILE"
+    )]
+    pub profile: Option<String>,
+
+    /// Specify maximum depth of directories to descend into when searching for repositories and files
+    #[arg(
+        global = true,
+        long = "max-depth",
+        value_name = "NUM",
+        env = "MAX_DEPTH"
+    )]
+    pub max_depth: Option<usize>,
+
+    /// Follow symlinked directories and select symlinked files.
+    /// By default symlinks are skipped so files are never rewritten through a link
+    #[arg(global = true, long = "follow-symlinks", action=ArgAction::SetTrue, env = "FOLLOW_SYMLINKS")]
+    pub follow_symlinks: bool,
+
+    /// Include submodules of found repositories and update superprojects' gitlinks after committing
+    #[arg(global = true, long = "submodules", action=ArgAction::SetTrue, env = "SUBMODULES")]
+    pub submodules: bool,
+
+    /// Specify Regex pattern for repository path relative to --path
+    #[arg(
+        global = true,
+        long = "repo",
+        value_name = "REGEX",
+        env = "REPO_PATTERN"
+    )]
+    pub repo_pattern: Option<String>,
+
+    /// Specify Regex pattern for URL of repository's remote selected with `--remote`
+    #[arg(
+        global = true,
+        long = "remote-url",
+        value_name = "REGEX",
+        env = "REMOTE_URL_PATTERN"
+    )]
+    pub remote_url_pattern: Option<String>,
+
+    /// Only select repositories containing a file matching glob, i.e. `Chart.yaml`. Can be repeated
+    #[arg(
+        global = true,
+        long = "has-file",
+        value_name = "GLOB",
+        env = "REPO_HAS_FILE"
+    )]
+    pub has_files: Vec<String>,
+
+    /// Specify file with repository path globs to allow, one per line. Lines starting with `!` deny
+    #[arg(
+        global = true,
+        long = "repo-list",
+        value_name = "FILE",
+        env = "REPO_LIST"
+    )]
+    pub repo_list: Option<String>,
+
+    /// Specify Regex pattern for branches to checkout. Each matching branch is changed in turn.
+    /// `@current` selects current branch, `@default` the branch `HEAD` of remote points to
+    /// and `tag:REGEX` checks out matching tags with detached HEAD.
+    /// Defaults to all branches when committing, otherwise and for `search` and `replace`
+    /// to the current branch
     #[arg(
+        global = true,
         short = 'b',
         long = "branch",
         value_name = "REGEX",
-        default_value = r".*",
         env = "REPO_BRANCH"
     )]
-    pub branch_pattern: String,
+    pub branch_pattern: Option<String>,
+
+    /// Pick a single branch or tag per repository when several match `--branch`
+    #[arg(
+        global = true,
+        long = "branch-pick",
+        value_name = "PICK",
+        env = "BRANCH_PICK"
+    )]
+    pub branch_pick: Option<BranchPick>,
+
+    /// Stay on checked out branches instead of restoring the original HEAD of each repository
+    /// at the end of the run
+    #[arg(global = true, long = "stay", action=ArgAction::SetTrue, env = "STAY")]
+    pub stay: bool,
+
+    /// Change branches in a temporary linked worktree per repository,
+    /// leaving working copies and their uncommitted changes untouched.
+    /// Worktrees with uncommitted or unpushed changes are kept and their path printed
+    #[arg(global = true, long = "worktree", action=ArgAction::SetTrue, env = "WORKTREE")]
+    pub worktree: bool,
 
     /// Specify Regex pattern for filename
-    #[arg(short = 'f', long = "file", value_name = "REGEX", env = "FILE_PATTERN")]
+    #[arg(
+        global = true,
+        short = 'f',
+        long = "file",
+        value_name = "REGEX",
+        env = "FILE_PATTERN"
+    )]
     pub file_pattern: Option<String>,
 
+    /// Specify Regex pattern for file path relative to repository root. Can be repeated
+    #[arg(
+        global = true,
+        short = 'P',
+        long = "path-regex",
+        value_name = "REGEX",
+        env = "PATH_PATTERN"
+    )]
+    pub path_patterns: Vec<String>,
+
+    /// Specify gitignore style glob for files to include, i.e. `**/deploy/*.yaml`. Can be repeated
+    #[arg(
+        global = true,
+        long = "include",
+        value_name = "GLOB",
+        env = "FILE_INCLUDE"
+    )]
+    pub include: Vec<String>,
+
+    /// Specify gitignore style glob for files and directories to exclude, i.e. `test/`. Can be repeated.
+    /// Without other file selectors all other files are selected
+    #[arg(
+        global = true,
+        long = "exclude",
+        value_name = "GLOB",
+        env = "FILE_EXCLUDE"
+    )]
+    pub exclude: Vec<String>,
+
     /// Specify Regex pattern for selecting lines
-    #[arg(short = 'l', long = "line", value_name = "REGEX", env = "LINE_PATTERN")]
+    #[arg(
+        global = true,
+        short = 'l',
+        long = "line",
+        value_name = "REGEX",
+        env = "LINE_PATTERN"
+    )]
     pub line_pattern: Option<String>,
 
+    /// Specify Regex pattern for excluding lines, i.e. `^\s*#`. Can be repeated
+    #[arg(
+        global = true,
+        long = "not-line",
+        value_name = "REGEX",
+        env = "NOT_LINE_PATTERN"
+    )]
+    pub not_line_patterns: Vec<String>,
+
+    /// Specify Regex pattern that must match within `--near-lines` of a selected line
+    #[arg(
+        global = true,
+        long = "near",
+        value_name = "REGEX",
+        env = "NEAR_PATTERN"
+    )]
+    pub near_pattern: Option<String>,
+
+    /// Specify maximum distance in lines for `--near`
+    #[arg(
+        global = true,
+        long = "near-lines",
+        value_name = "N",
+        default_value_t = 3,
+        requires = "near_pattern",
+        env = "NEAR_LINES"
+    )]
+    pub near_lines: usize,
+
+    /// Specify Regex pattern for header of indentation block or `[section]` that must enclose a selected line
+    #[arg(
+        global = true,
+        long = "section",
+        value_name = "REGEX",
+        env = "SECTION_PATTERN"
+    )]
+    pub section_pattern: Option<String>,
+
+    /// Specify maximum size of files to search, i.e. `512K` or `10M`. Larger files are skipped
+    #[arg(global = true, long = "max-file-size", value_name = "SIZE", value_parser = func::parse_size, env = "MAX_FILE_SIZE")]
+    pub max_file_size: Option<u64>,
+
     /// Specify Regex pattern for selecting parts of a line
     #[arg(
+        global = true,
         short = 's',
         long = "select",
         value_name = "REGEX",
@@ -41,8 +227,13 @@ pub struct Config {
     )]
     pub line_select_pattern: Option<String>,
 
-    /// Specify Regex pattern for replacing lines selected by --select
+    /// Specify replacement template for parts of lines selected by --select.
+    /// `$1` and `$name` expand groups captured by --select and named groups captured by --line,
+    /// `${name:upper}`, `${name:lower}` and `${name:title}` transform case,
+    /// `${repo}`, `${branch}` and `${file}` expand to current repository, branch and file
+    /// and `$$` is a literal `$`
     #[arg(
+        global = true,
         short = 'r',
         long = "replace",
         value_name = "REGEX",
@@ -50,27 +241,589 @@ pub struct Config {
     )]
     pub line_replace_pattern: Option<String>,
 
+    /// Use --replace as a literal string without expanding groups and placeholders
+    #[arg(global = true, long = "literal-replace", action=ArgAction::SetTrue, env = "LITERAL_REPLACE")]
+    pub literal_replace: bool,
+
+    /// Specify key path to edit in YAML, JSON and TOML files, i.e. `kafka.bootstrap` or `$.spec.replicas`
+    #[arg(
+        global = true,
+        short = 'k',
+        long = "key",
+        value_name = "PATH",
+        env = "KEY_PATH"
+    )]
+    pub key_path: Option<String>,
+
+    /// Specify new value for key selected by --key
+    #[arg(
+        global = true,
+        long = "value",
+        value_name = "TXT",
+        requires = "key_path",
+        env = "KEY_VALUE"
+    )]
+    pub value: Option<String>,
+
+    /// Specify shell command to transform each matched file instead of replacing lines.
+    /// `{}` is substituted with file path relative to repository root
+    #[arg(
+        global = true,
+        short = 'x',
+        long = "exec",
+        value_name = "CMD",
+        env = "EXEC_CMD"
+    )]
+    pub exec: Option<String>,
+
+    /// Run --exec command once in each repository root instead of per file
+    #[arg(global = true, long = "exec-root", action=ArgAction::SetTrue, requires = "exec", env = "EXEC_ROOT")]
+    pub exec_root: bool,
+
+    /// Treat --file, --line and --select as literal strings and --replace as literal replacement
+    #[arg(global = true, short = 'F', long = "fixed-strings", action=ArgAction::SetTrue, env = "FIXED_STRINGS")]
+    pub fixed_strings: bool,
+
+    /// Match --file, --line and --select case insensitively
+    #[arg(global = true, short = 'i', long = "ignore-case", action=ArgAction::SetTrue, env = "IGNORE_CASE")]
+    pub ignore_case: bool,
+
+    /// Match --file, --line and --select only as whole words
+    #[arg(global = true, short = 'w', long = "word-regexp", action=ArgAction::SetTrue, env = "WORD_REGEXP")]
+    pub word_regexp: bool,
+
     /// Specify commit message. No commit if empty.
-    #[arg(short = 'c', long = "commit", value_name = "TXT", env = "COMMIT_MSG")]
+    #[arg(
+        global = true,
+        short = 'c',
+        long = "commit",
+        value_name = "TXT",
+        env = "COMMIT_MSG"
+    )]
     pub commit: Option<String>,
 
+    /// Specify commit author as `Name <email>`. User from git config is used if not specified
+    #[arg(
+        global = true,
+        long = "author",
+        value_name = "TXT",
+        env = "COMMIT_AUTHOR"
+    )]
+    pub author: Option<String>,
+
+    /// Run repository's pre-commit and commit-msg hooks before committing
+    #[arg(global = true, long = "hooks", action=ArgAction::SetTrue, env = "RUN_HOOKS")]
+    pub hooks: bool,
+
+    /// Specify shell command to validate changes in each repository before committing.
+    /// Changes are rolled back if the command fails
+    #[arg(global = true, long = "check", value_name = "CMD", env = "CHECK_CMD")]
+    pub check: Option<String>,
+
+    /// Amend previous commit if it was made by gitraider instead of creating a new one.
+    /// Pushing after amending overwrites remote branch if it is still at the amended commit
+    #[arg(global = true, long = "amend", action=ArgAction::SetTrue, env = "COMMIT_AMEND")]
+    pub amend: bool,
+
+    /// Sign commits even if `commit.gpgsign` is not set in git config
+    #[arg(global = true, short = 'S', long = "sign", action=ArgAction::SetTrue, env = "COMMIT_SIGN")]
+    pub sign: bool,
+
+    /// Do not sign commits even if `commit.gpgsign` is set in git config
+    #[arg(global = true, long = "no-sign", action=ArgAction::SetTrue, conflicts_with = "sign", env = "COMMIT_NO_SIGN")]
+    pub no_sign: bool,
+
+    /// Specify signing key. Defaults to `user.signingkey` from git config
+    #[arg(
+        global = true,
+        long = "signing-key",
+        value_name = "KEY",
+        env = "SIGNING_KEY"
+    )]
+    pub signing_key: Option<String>,
+
+    /// Specify signature format. Defaults to `gpg.format` from git config
+    #[arg(
+        global = true,
+        long = "signing-format",
+        value_name = "FORMAT",
+        env = "SIGNING_FORMAT"
+    )]
+    pub signing_format: Option<SignFormat>,
+
     /// Specify wether to push commit
-    #[arg(long = "push", action=ArgAction::SetTrue, env = "PUSH_CHANGES")]
+    #[arg(global = true, long = "push", action=ArgAction::SetTrue, env = "PUSH_CHANGES")]
     pub push: bool,
 
+    /// Specify remote to push to and to resolve `@default` and branches missing locally from
+    #[arg(
+        global = true,
+        long = "remote",
+        value_name = "NAME",
+        default_value = "origin",
+        env = "GIT_REMOTE"
+    )]
+    pub remote: String,
+
     /// Specify git username for push
-    #[arg(long = "username", value_name = "TXT", env = "GIT_USER")]
+    #[arg(global = true, long = "username", value_name = "TXT", env = "GIT_USER")]
     pub username: Option<String>,
 
     /// Specify git username password for push
-    #[arg(long = "password", value_name = "TXT", env = "GIT_PASSWORD")]
+    #[arg(
+        global = true,
+        long = "password",
+        value_name = "TXT",
+        env = "GIT_PASSWORD"
+    )]
     pub password: Option<String>,
 
+    /// Abort if any file would have more changed lines than this
+    #[arg(
+        global = true,
+        long = "max-changes-per-file",
+        value_name = "N",
+        env = "MAX_CHANGES_PER_FILE"
+    )]
+    pub max_changes_per_file: Option<usize>,
+
+    /// Abort if any repository would have more changed files than this
+    #[arg(
+        global = true,
+        long = "max-files-per-repo",
+        value_name = "N",
+        env = "MAX_FILES_PER_REPO"
+    )]
+    pub max_files_per_repo: Option<usize>,
+
+    /// Abort if more repositories than this would be changed
+    #[arg(global = true, long = "max-repos", value_name = "N", env = "MAX_REPOS")]
+    pub max_repos: Option<usize>,
+
+    /// Review each proposed replacement before writing files,
+    /// and each file changed by --exec as a diff
+    #[arg(global = true, long = "interactive", action=ArgAction::SetTrue, env = "INTERACTIVE")]
+    pub interactive: bool,
+
+    /// Write changes without asking for confirmation
+    #[arg(global = true, short = 'y', long = "yes", action=ArgAction::SetTrue, env = "ASSUME_YES")]
+    pub yes: bool,
+
     /// Run program in dry mode without altering files and writing to git history
-    #[arg(long = "dry", action=ArgAction::SetTrue, env = "DRY_RUN")]
+    #[arg(global = true, long = "dry", action=ArgAction::SetTrue, env = "DRY_RUN")]
     pub dry_run: bool,
 
     /// Display assessment at the end of program execution
-    #[arg(short = 'a', long = "assess", action=ArgAction::SetTrue, env = "DISPLAY_ASSESS")]
+    #[arg(global = true, short = 'a', long = "assess", action=ArgAction::SetTrue, env = "DISPLAY_ASSESS")]
     pub assess: bool,
 }
+
+/// Subcommands. Without a subcommand the whole pipeline runs as configured by flags,
+/// with each stage also runnable on its own
+#[derive(Subcommand, Clone, Debug, PartialEq, Eq)]
+pub enum Command {
+    /// Report matches and proposed replacements without changing files
+    Search,
+
+    /// Edit matched files and stage them
+    Replace,
+
+    /// Validate and commit staged changes
+    Commit,
+
+    /// Push commits not yet pushed to upstream
+    Push,
+
+    /// Show branch, dirty state, commits ahead and behind upstream and last commit of all repositories
+    Status {
+        /// Output format
+        #[arg(long = "format", value_enum, default_value_t = OutputFormat::Table)]
+        format: OutputFormat,
+    },
+
+    /// Browse matches in a terminal UI, toggle them and apply, commit or push selected ones
+    Browse,
+
+    /// Write planned changes, branches and commit message to a file for review instead of applying them
+    Plan {
+        /// Plan file to write
+        #[arg(
+            short = 'o',
+            long = "output",
+            value_name = "FILE",
+            default_value = "gitraider-plan.json"
+        )]
+        output: PathBuf,
+    },
+
+    /// Inspect configuration
+    Config {
+        #[command(subcommand)]
+        action: ConfigCommand,
+    },
+
+    /// Execute a plan file, resuming from the first incomplete repository
+    ApplyPlan {
+        /// Plan file to execute
+        #[arg(value_name = "FILE", default_value = "gitraider-plan.json")]
+        file: PathBuf,
+    },
+}
+
+/// Configuration subcommands
+#[derive(Subcommand, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfigCommand {
+    /// Print effective configuration with the source of each value
+    Show,
+}
+
+/// Output format of reports
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    Table,
+    Json,
+}
+
+/// Name of project configuration file, looked up in current directory
+pub const PROJECT_FILE: &str = ".gitraider.toml";
+
+/// Effective value of a setting and where it came from
+#[derive(Clone, Debug)]
+pub struct Setting {
+    pub name: String,
+    pub value: String,
+    pub source: String,
+}
+
+/// Value of an argument read from a configuration file or environment variable
+struct FileValue {
+    id: String,
+    values: Vec<String>,
+    source: String,
+}
+
+impl Config {
+    /// Parse configuration from configuration files, environment variables and flags,
+    /// in order of increasing precedence. Returns effective settings with their sources.
+    ///
+    /// Settings are read from `~/.config/gitraider/config.toml`, then `.gitraider.toml`,
+    /// keyed by long flag names. Settings of `[profile.NAME]` selected with `--profile`
+    /// override top level settings of the same file
+    #[must_use]
+    pub fn load() -> (Self, Vec<Setting>) {
+        let files: Vec<(PathBuf, String)> = config_files()
+            .into_iter()
+            .filter_map(|path| fs::read_to_string(&path).ok().map(|c| (path, c)))
+            .collect();
+        Self::merge(
+            std::env::args_os().collect(),
+            |name| std::env::var(name).ok(),
+            &files,
+        )
+    }
+
+    /// Merge contents of configuration files, environment variables read with `env`
+    /// and command line `args`, in order of increasing precedence
+    fn merge(
+        args: Vec<OsString>,
+        env: impl Fn(&str) -> Option<String>,
+        files: &[(PathBuf, String)],
+    ) -> (Self, Vec<Setting>) {
+        let mut cmd = Self::command();
+        let profile = profile_arg(&args).or_else(|| env("GITRAIDER_PROFILE"));
+        let mut found_profile = false;
+
+        // Later files and profiles override earlier values
+        let mut file_values: Vec<FileValue> = Vec::new();
+        for (path, contents) in files {
+            let doc: DocumentMut = contents.parse().unwrap_or_else(|e| {
+                panic!("ERROR: Invalid configuration file {}: {e}", path.display())
+            });
+            let source = format!("file {}", path.display());
+            let mut values = file_table(&cmd, doc.as_table(), &source);
+            if let Some(name) = &profile {
+                if let Some(table) = doc
+                    .get("profile")
+                    .and_then(|p| p.get(name))
+                    .and_then(Item::as_table)
+                {
+                    found_profile = true;
+                    values.extend(file_table(
+                        &cmd,
+                        table,
+                        &format!("{source} [profile.{name}]"),
+                    ));
+                }
+            }
+            for value in values {
+                file_values.retain(|v| v.id != value.id);
+                file_values.push(value);
+            }
+        }
+        if let (Some(name), false) = (&profile, found_profile) {
+            panic!("ERROR: Profile '{name}' not found in configuration files");
+        }
+
+        // Environment variables override files
+        let env_values: Vec<FileValue> = cmd
+            .get_arguments()
+            .filter_map(|arg| {
+                let name = arg.get_env()?.to_string_lossy().to_string();
+                Some(FileValue {
+                    id: arg.get_id().to_string(),
+                    values: vec![env(&name)?],
+                    source: format!("env {name}"),
+                })
+            })
+            .collect();
+        for value in env_values {
+            file_values.retain(|v| v.id != value.id);
+            file_values.push(value);
+        }
+
+        // File and env values become defaults, so that flags take precedence
+        for value in &file_values {
+            let values = value.values.clone();
+            cmd = cmd.mut_arg(value.id.as_str(), |a| a.default_values(values));
+        }
+        let matches = cmd
+            .try_get_matches_from_mut(args)
+            .unwrap_or_else(|e| e.exit());
+        let config = Self::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+
+        // Keep order of flags in help, which file defaults change
+        let mut args: Vec<&clap::Arg> = cmd.get_arguments().collect();
+        let order: Vec<String> = Self::command()
+            .get_arguments()
+            .map(|a| a.get_id().to_string())
+            .collect();
+        args.sort_by_key(|a| order.iter().position(|id| id == a.get_id()));
+
+        let settings = args
+            .into_iter()
+            .filter(|a| !matches!(a.get_id().as_str(), "help" | "version"))
+            .filter_map(|arg| {
+                let id = arg.get_id().as_str();
+                let values: Vec<String> = matches
+                    .get_raw(id)?
+                    .map(|v| v.to_string_lossy().to_string())
+                    .collect();
+                let source = match matches.value_source(id)? {
+                    ValueSource::CommandLine => "flag".to_string(),
+                    ValueSource::EnvVariable => {
+                        format!("env {}", arg.get_env()?.to_string_lossy())
+                    }
+                    _ => file_values
+                        .iter()
+                        .find(|v| v.id == id)
+                        .map_or_else(|| "default".to_string(), |v| v.source.clone()),
+                };
+                Some(Setting {
+                    name: arg.get_long().unwrap_or(id).to_string(),
+                    value: if id == "password" {
+                        "********".to_string()
+                    } else {
+                        values.join(", ")
+                    },
+                    source,
+                })
+            })
+            .collect();
+
+        (config, settings)
+    }
+
+    /// Signing options with command line overrides
+    #[must_use]
+    pub fn sign_options(&self) -> SignOptions {
+        let enabled = match (self.sign, self.no_sign) {
+            (true, _) => Some(true),
+            (_, true) => Some(false),
+            _ => None,
+        };
+        SignOptions {
+            enabled,
+            key: self.signing_key.clone(),
+            format: self.signing_format,
+        }
+    }
+
+    /// Branch selector, `None` for the current branch.
+    /// All branches are changed by default only when committing them
+    #[must_use]
+    pub fn branches(&self) -> Option<&str> {
+        let all = self.commit.is_some()
+            && !matches!(self.command, Some(Command::Search | Command::Replace));
+        self.branch_pattern
+            .as_deref()
+            .or_else(|| all.then_some(".*"))
+    }
+
+    /// Fail-safe limits on number of changes
+    #[must_use]
+    pub const fn limits(&self) -> Limits {
+        Limits {
+            max_changes_per_file: self.max_changes_per_file,
+            max_files_per_repo: self.max_files_per_repo,
+            max_repos: self.max_repos,
+        }
+    }
+}
+
+/// Get profile name from flags before parsing
+fn profile_arg(args: &[OsString]) -> Option<String> {
+    let mut args = args.iter().skip(1).map(|a| a.to_string_lossy());
+    while let Some(arg) = args.next() {
+        if arg == "--profile" {
+            return args.next().map(|a| a.to_string());
+        }
+        if let Some(name) = arg.strip_prefix("--profile=") {
+            return Some(name.to_string());
+        }
+    }
+    None
+}
+
+/// Paths of user and project configuration files in order of precedence
+fn config_files() -> Vec<PathBuf> {
+    let user_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".config")));
+    user_dir
+        .map(|d| d.join("gitraider").join("config.toml"))
+        .into_iter()
+        .chain(std::iter::once(PathBuf::from(PROJECT_FILE)))
+        .collect()
+}
+
+/// Read top level settings of a table, keyed by long flag names.
+/// Keys that are not long flag names are reported and ignored
+fn file_table(cmd: &clap::Command, table: &Table, source: &str) -> Vec<FileValue> {
+    table
+        .iter()
+        .filter(|(key, _)| *key != "profile")
+        .filter_map(|(key, item)| {
+            let Some(arg) = cmd
+                .get_arguments()
+                .find(|a| a.get_long() == Some(key) || a.get_id() == key)
+            else {
+                eprintln!("WARNING: Unsupported setting '{key}' in {source} ignored, keys are long flag names like 'branch' or 'remote'");
+                return None;
+            };
+            let values = match item.as_value() {
+                Some(Value::Array(array)) => array.iter().map(value_string).collect(),
+                Some(value) => vec![value_string(value)],
+                None => panic!("ERROR: Invalid value of setting '{key}' in {source}"),
+            };
+            Some(FileValue {
+                id: arg.get_id().to_string(),
+                values,
+                source: source.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Convert a TOML value to an argument value
+fn value_string(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.value().clone(),
+        other => other.to_string().trim().to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    const USER_FILE: &str = "
+branch = 'user'
+remote = 'upstream'
+commit = 'user message'
+max-depth = 3
+stay = true
+
+[profile.ci]
+commit = 'ci message'
+";
+
+    const PROJECT_FILE: &str = "
+branch = 'project'
+remote = 'project'
+
+[profile.ci]
+remote = 'ci'
+";
+
+    fn merge(args: &[&str], env: &[(&str, &str)]) -> (Config, Vec<Setting>) {
+        let args = std::iter::once("gitraider")
+            .chain(args.iter().copied())
+            .map(OsString::from)
+            .collect();
+        let env: HashMap<String, String> = env
+            .iter()
+            .map(|(k, v)| ((*k).to_string(), (*v).to_string()))
+            .collect();
+        let files = [
+            (PathBuf::from("user.toml"), USER_FILE.to_string()),
+            (PathBuf::from("project.toml"), PROJECT_FILE.to_string()),
+        ];
+        Config::merge(args, |name| env.get(name).cloned(), &files)
+    }
+
+    fn source<'a>(settings: &'a [Setting], name: &str) -> &'a str {
+        settings
+            .iter()
+            .find(|s| s.name == name)
+            .map_or("", |s| s.source.as_str())
+    }
+
+    #[test]
+    fn config_precedence() {
+        // Project file overrides user file
+        let (conf, settings) = merge(&[], &[]);
+        assert_eq!(conf.branch_pattern.as_deref(), Some("project"));
+        assert_eq!(conf.remote, "project");
+        assert_eq!(conf.commit.as_deref(), Some("user message"));
+        assert_eq!(conf.max_depth, Some(3));
+        assert!(conf.stay);
+        assert_eq!(source(&settings, "branch"), "file project.toml");
+        assert_eq!(source(&settings, "commit"), "file user.toml");
+
+        // Environment overrides files, flags override environment
+        let env = [("REPO_BRANCH", "env"), ("GIT_REMOTE", "env")];
+        let (conf, settings) = merge(&["-b", "flag"], &env);
+        assert_eq!(conf.branch_pattern.as_deref(), Some("flag"));
+        assert_eq!(conf.remote, "env");
+        assert_eq!(source(&settings, "branch"), "flag");
+        assert_eq!(source(&settings, "remote"), "env GIT_REMOTE");
+    }
+
+    #[test]
+    fn config_profile() {
+        // Profile overrides top level settings of its own and earlier files
+        let (conf, settings) = merge(&["--profile", "ci"], &[]);
+        assert_eq!(conf.commit.as_deref(), Some("ci message"));
+        assert_eq!(conf.remote, "ci");
+        assert_eq!(conf.branch_pattern.as_deref(), Some("project"));
+        assert_eq!(
+            source(&settings, "remote"),
+            "file project.toml [profile.ci]"
+        );
+
+        // Profile selected by environment, flags still take precedence
+        let env = [("GITRAIDER_PROFILE", "ci")];
+        let (conf, _) = merge(&["--remote=flag"], &env);
+        assert_eq!(conf.commit.as_deref(), Some("ci message"));
+        assert_eq!(conf.remote, "flag");
+        let (conf, _) = merge(&["--profile=ci", "-c", "flag"], &[]);
+        assert_eq!(conf.commit.as_deref(), Some("flag"));
+    }
+
+    #[test]
+    #[should_panic(expected = "Profile 'missing' not found")]
+    fn config_profile_missing() {
+        let _ = merge(&["--profile", "missing"], &[]);
+    }
+}
@@ -0,0 +1,339 @@
+use std::fs;
+use std::path::Path;
+
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use regex::Regex;
+
+use crate::func;
+use crate::structs::Directory;
+
+/// File selector combining filename regex, repo relative path regexes
+/// and gitignore style include and exclude globs.
+/// A file is selected if it matches all given selectors and no exclude glob,
+/// so exclude globs on their own select all other files
+pub struct FileSelector {
+    pub name: Option<Regex>,
+    pub paths: Vec<Regex>,
+    pub include: Option<GlobSet>,
+    pub exclude: Option<GlobSet>,
+}
+
+impl FileSelector {
+    #[must_use]
+    pub fn new(
+        name: Option<Regex>,
+        paths: &[String],
+        include: &[String],
+        exclude: &[String],
+    ) -> Self {
+        Self {
+            name,
+            paths: paths
+                .iter()
+                .map(|p| Regex::new(p).expect("Error compiling regex"))
+                .collect(),
+            include: glob_set(include),
+            exclude: glob_set(exclude),
+        }
+    }
+
+    /// Check if no selectors were specified
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.name.is_none()
+            && self.paths.is_empty()
+            && self.include.is_none()
+            && self.exclude.is_none()
+    }
+
+    /// Check if file path relative to repository root is selected
+    #[must_use]
+    pub fn is_match(&self, relative: &Path) -> bool {
+        let file_name = relative
+            .file_name()
+            .map(|n| n.to_string_lossy())
+            .unwrap_or_default();
+        let path = relative.to_string_lossy();
+
+        self.name.as_ref().is_none_or(|re| re.is_match(&file_name))
+            && (self.paths.is_empty() || self.paths.iter().any(|re| re.is_match(&path)))
+            && self.include.as_ref().is_none_or(|g| g.is_match(relative))
+            && !self.is_excluded(relative)
+    }
+
+    /// Check if path relative to repository root is excluded.
+    /// Used to skip whole directories
+    #[must_use]
+    pub fn is_excluded(&self, relative: &Path) -> bool {
+        self.exclude.as_ref().is_some_and(|g| g.is_match(relative))
+    }
+}
+
+/// Line filter combining a line regex with negative patterns
+/// and context conditions on surrounding lines.
+/// A line is selected if it matches the line regex, no negative pattern,
+/// has a line matching `near` within given distance
+/// and lies inside a block whose header matches `section`
+pub struct LineFilter {
+    pub line: Regex,
+    pub not_lines: Vec<Regex>,
+    pub near: Option<(Regex, usize)>,
+    pub section: Option<Regex>,
+}
+
+impl LineFilter {
+    #[must_use]
+    pub const fn new(line: Regex) -> Self {
+        Self {
+            line,
+            not_lines: Vec::new(),
+            near: None,
+            section: None,
+        }
+    }
+
+    /// Check if line `n` of given lines is selected
+    #[must_use]
+    pub fn is_match(&self, lines: &[String], n: usize) -> bool {
+        let content = lines[n].as_str();
+        self.line.is_match(content)
+            && !self.not_lines.iter().any(|re| re.is_match(content))
+            && self.near.as_ref().is_none_or(|(re, distance)| {
+                let end = (n + distance + 1).min(lines.len());
+                (n.saturating_sub(*distance)..end).any(|i| i != n && re.is_match(&lines[i]))
+            })
+            && self
+                .section
+                .as_ref()
+                .is_none_or(|re| enclosing_headers(lines, n).any(|h| re.is_match(h)))
+    }
+}
+
+/// Iterate over headers of blocks enclosing line `n`:
+/// preceding lines with less indentation, innermost first,
+/// followed by the nearest `[section]` header of INI and TOML files
+fn enclosing_headers(lines: &[String], n: usize) -> impl Iterator<Item = &str> {
+    let indent = |l: &str| l.len() - l.trim_start().len();
+    let mut current = indent(&lines[n]);
+    let mut headers = Vec::new();
+    for line in lines[..n].iter().rev() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        if indent(line) < current {
+            current = indent(line);
+            headers.push(line.as_str());
+        }
+    }
+    let table = lines[..n]
+        .iter()
+        .rev()
+        .find(|l| l.trim_start().starts_with('['));
+    headers.into_iter().chain(table.map(String::as_str))
+}
+
+/// Repository filter by relative path, remote's URL, file presence
+/// and an allow/deny list file
+pub struct RepoFilter {
+    pub name: Option<Regex>,
+    pub remote_url: Option<Regex>,
+    pub has_files: Vec<(String, FileSelector)>,
+    pub allow: Option<GlobSet>,
+    pub deny: Option<GlobSet>,
+}
+
+impl RepoFilter {
+    /// Create filter, reading allow/deny list file if given.
+    /// List file contains one repository path glob per line,
+    /// lines starting with `!` deny a repository and `#` are comments
+    #[must_use]
+    pub fn new(
+        name: Option<&str>,
+        remote_url: Option<&str>,
+        has_files: &[String],
+        list_file: Option<&str>,
+    ) -> Self {
+        let (mut allow, mut deny) = (Vec::new(), Vec::new());
+        if let Some(list_file) = list_file {
+            fs::read_to_string(list_file)
+                .expect("Error reading repository list file")
+                .lines()
+                .map(str::trim)
+                .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                .for_each(|l| match l.strip_prefix('!') {
+                    Some(denied) => deny.push(denied.to_string()),
+                    None => allow.push(l.to_string()),
+                });
+        }
+
+        Self {
+            name: name.map(|p| Regex::new(p).expect("Error compiling regex")),
+            remote_url: remote_url.map(|p| Regex::new(p).expect("Error compiling regex")),
+            has_files: has_files
+                .iter()
+                .map(|f| {
+                    (
+                        f.clone(),
+                        FileSelector::new(None, &[], std::slice::from_ref(f), &[]),
+                    )
+                })
+                .collect(),
+            allow: glob_set(&allow),
+            deny: glob_set(&deny),
+        }
+    }
+
+    /// Check if no filters were specified
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.name.is_none()
+            && self.remote_url.is_none()
+            && self.has_files.is_empty()
+            && self.allow.is_none()
+            && self.deny.is_none()
+    }
+
+    /// Check if repository passes the filter, returning reason if it does not.
+    /// Remote URL is read from the given `remote`
+    pub fn check(&self, dir: &Directory, remote: &str) -> Result<(), String> {
+        let relative = dir.relative_path.as_path();
+        let path = relative.to_string_lossy();
+
+        if self.deny.as_ref().is_some_and(|g| g.is_match(relative)) {
+            return Err("denied by repository list".to_string());
+        }
+        if self.allow.as_ref().is_some_and(|g| !g.is_match(relative)) {
+            return Err("not in repository list".to_string());
+        }
+        if let Some(re) = &self.name {
+            if !re.is_match(&path) {
+                return Err(format!("path does not match '{re}'"));
+            }
+        }
+        if let Some(re) = &self.remote_url {
+            let url = dir
+                .repo
+                .as_ref()
+                .and_then(|r| r.find_remote(remote).ok())
+                .and_then(|r| r.url().map(str::to_string));
+            match url {
+                None => return Err(format!("no {remote} remote")),
+                Some(url) if !re.is_match(&url) => {
+                    return Err(format!("{remote} '{url}' does not match '{re}'"))
+                }
+                Some(_) => {}
+            }
+        }
+        for (pattern, selector) in &self.has_files {
+            if func::find_files_by(&dir.path, selector, &func::WalkOptions::default()).is_empty() {
+                return Err(format!("no file matching '{pattern}'"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Build a glob set with gitignore style semantics:
+/// patterns without a slash match at any depth,
+/// a leading slash anchors to repository root
+/// and directory patterns match everything inside
+fn glob_set(patterns: &[String]) -> Option<GlobSet> {
+    if patterns.is_empty() {
+        return None;
+    }
+
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let trimmed = pattern.trim_end_matches('/');
+        let base = match trimmed.strip_prefix('/') {
+            Some(anchored) => anchored.to_string(),
+            None if trimmed.contains('/') => trimmed.to_string(),
+            None => format!("**/{trimmed}"),
+        };
+        for glob in [base.clone(), format!("{base}/**")] {
+            builder.add(
+                GlobBuilder::new(&glob)
+                    .literal_separator(true)
+                    .build()
+                    .expect("Error compiling glob"),
+            );
+        }
+    }
+    Some(builder.build().expect("Error compiling glob set"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(text: &str) -> Vec<String> {
+        text.lines().map(str::to_string).collect()
+    }
+
+    fn globs(patterns: &[&str]) -> GlobSet {
+        let patterns: Vec<String> = patterns.iter().map(|p| (*p).to_string()).collect();
+        glob_set(&patterns).unwrap()
+    }
+
+    #[test]
+    fn glob_set_unanchored() {
+        let set = globs(&["*.yaml", "Chart.lock"]);
+        assert!(set.is_match("values.yaml"));
+        assert!(set.is_match("charts/app/values.yaml"));
+        assert!(set.is_match("charts/Chart.lock"));
+        assert!(!set.is_match("values.json"));
+    }
+
+    #[test]
+    fn glob_set_anchored() {
+        let set = globs(&["/values.yaml", "charts/*.yaml"]);
+        assert!(set.is_match("values.yaml"));
+        assert!(!set.is_match("app/values.yaml"));
+        assert!(set.is_match("charts/a.yaml"));
+        assert!(!set.is_match("app/charts/a.yaml"));
+        assert!(!set.is_match("charts/app/a.yaml"));
+    }
+
+    #[test]
+    fn glob_set_directory() {
+        let set = globs(&["test/"]);
+        assert!(set.is_match("test"));
+        assert!(set.is_match("test/values.yaml"));
+        assert!(set.is_match("app/test/deep/values.yaml"));
+        assert!(!set.is_match("tests/values.yaml"));
+    }
+
+    #[test]
+    fn file_selector_exclude_only() {
+        let selector = FileSelector::new(None, &[], &[], &["test/".to_string()]);
+        assert!(!selector.is_empty());
+        assert!(selector.is_match(Path::new("values.yaml")));
+        assert!(!selector.is_match(Path::new("test/values.yaml")));
+        assert!(selector.is_excluded(Path::new("app/test")));
+
+        let selector =
+            FileSelector::new(None, &[], &["*.yaml".to_string()], &["/a.yaml".to_string()]);
+        assert!(selector.is_match(Path::new("b.yaml")));
+        assert!(selector.is_match(Path::new("x/a.yaml")));
+        assert!(!selector.is_match(Path::new("a.yaml")));
+        assert!(!selector.is_match(Path::new("b.json")));
+    }
+
+    #[test]
+    fn line_filter_context() {
+        let lines =
+            lines("kafka:\n  host: prod-kafka\n  # host: prod-kafka\nredis:\n  host: prod-kafka\n");
+        let mut filter = LineFilter::new(Regex::new("prod-kafka").unwrap());
+        filter.not_lines.push(Regex::new(r"^\s*#").unwrap());
+        filter.section = Some(Regex::new("^kafka:").unwrap());
+        let selected: Vec<usize> = (0..lines.len())
+            .filter(|&n| filter.is_match(&lines, n))
+            .collect();
+        assert_eq!(selected, vec![1]);
+
+        filter.section = None;
+        filter.near = Some((Regex::new("^redis:").unwrap(), 1));
+        assert!(!filter.is_match(&lines, 1));
+        assert!(filter.is_match(&lines, 4));
+    }
+}
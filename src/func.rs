@@ -1,68 +1,360 @@
-use regex::Regex;
+use std::collections::HashSet;
 use std::fs;
-use std::path::PathBuf;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::Command;
 
-/// Recursively find directories
+use regex::{Regex, RegexBuilder};
+
+use crate::filter::FileSelector;
+
+/// Options for compiling file, line and select patterns
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PatternOptions {
+    /// Treat patterns as literal strings instead of regular expressions
+    pub fixed: bool,
+    /// Match case insensitively
+    pub ignore_case: bool,
+    /// Match only whole words
+    pub word: bool,
+}
+
+/// Compile a pattern with pattern options
+#[must_use]
+pub fn build_regex(pattern: &str, opts: &PatternOptions) -> Regex {
+    let pattern = if opts.fixed {
+        regex::escape(pattern)
+    } else {
+        pattern.to_string()
+    };
+    let pattern = if opts.word {
+        format!(r"\b(?:{pattern})\b")
+    } else {
+        pattern
+    };
+    RegexBuilder::new(&pattern)
+        .case_insensitive(opts.ignore_case)
+        .build()
+        .expect("Error compiling regex")
+}
+
+/// Directory walking options
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WalkOptions {
+    /// Maximum depth of directories to descend into below the starting directory
+    pub max_depth: Option<usize>,
+    /// Whether to follow symlinked directories and select symlinked files
+    pub follow_symlinks: bool,
+}
+
+/// State of a directory walk guarding against symlink loops
+struct Walk {
+    opts: WalkOptions,
+    visited: HashSet<(u64, u64)>,
+}
+
+impl Walk {
+    fn new(opts: WalkOptions) -> Self {
+        Self {
+            opts,
+            visited: HashSet::new(),
+        }
+    }
+
+    /// Check if a directory at a given depth should be descended into
+    /// and mark it as visited
+    fn enter(&mut self, dir: &Path, depth: usize) -> bool {
+        if self.opts.max_depth.is_some_and(|max| depth > max) {
+            return false;
+        }
+        let is_symlink = fs::symlink_metadata(dir).is_ok_and(|m| m.file_type().is_symlink());
+        if is_symlink && !self.opts.follow_symlinks {
+            return false;
+        }
+        match fs::metadata(dir) {
+            Ok(meta) if meta.is_dir() => {
+                if self.visited.insert(file_id(dir, &meta)) {
+                    true
+                } else {
+                    eprintln!("Skipping already visited directory {}", dir.display());
+                    false
+                }
+            }
+            _ => false,
+        }
+    }
+
+    /// Check if a file should be selected
+    fn accept_file(&self, path: &Path) -> bool {
+        self.opts.follow_symlinks
+            || !fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_symlink())
+    }
+}
+
+/// Get unique id of a file, device and inode on unix
+#[cfg(unix)]
+fn file_id(_path: &Path, meta: &fs::Metadata) -> (u64, u64) {
+    use std::os::unix::fs::MetadataExt;
+    (meta.dev(), meta.ino())
+}
+
+/// Get unique id of a file from a hash of its canonical path
+#[cfg(not(unix))]
+fn file_id(path: &Path, _meta: &fs::Metadata) -> (u64, u64) {
+    use std::hash::{Hash, Hasher};
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    fs::canonicalize(path)
+        .unwrap_or_else(|_| path.to_path_buf())
+        .hash(&mut hasher);
+    (0, hasher.finish())
+}
+
+/// Recursively find directories containing a file or directory with a specific name,
+/// including the directory itself and directories nested in found ones
 #[must_use]
-pub fn find_dirs(dir: &PathBuf, name: &str, parent: &bool) -> Vec<PathBuf> {
+pub fn find_dirs(dir: &Path, name: &str, parent: &bool, opts: &WalkOptions) -> Vec<PathBuf> {
+    let mut walk = Walk::new(*opts);
     let mut result = Vec::new();
+    if walk.enter(dir, 0) {
+        find_dirs_walk(dir, name, *parent, &mut walk, 0, &mut result);
+    }
+    result
+}
+
+fn find_dirs_walk(
+    dir: &Path,
+    name: &str,
+    parent: bool,
+    walk: &mut Walk,
+    depth: usize,
+    result: &mut Vec<PathBuf>,
+) {
+    if dir.join(name).exists() {
+        match parent {
+            // Get path the directory itself
+            false => result.push(dir.to_path_buf()),
+            // Get path of parent of the directory instead of the directory itself
+            true => {
+                if let Some(parent) = dir.parent() {
+                    result.push(parent.to_path_buf());
+                }
+            }
+        }
+    }
 
     if let Ok(entries) = fs::read_dir(dir) {
         for entry in entries {
-            let path = entry.expect("Error unpacking path").path();
-            if path.is_dir() {
-                if fs::read_dir(&path.join(name)).is_ok() {
-                    match parent {
-                        // Get path the directory itself
-                        false => result.push(path.clone()),
-                        // Get path of parent of the directory instead of the directory itself
-                        true => {
-                            if let Some(parent) = path.parent() {
-                                result.push(parent.to_path_buf());
-                            }
-                        }
-                    }
-                } else {
-                    result.append(&mut find_dirs(&path, name, parent));
-                }
+            let entry = entry.expect("Error unpacking path");
+            let path = entry.path();
+            if entry.file_name() != name && walk.enter(&path, depth + 1) {
+                find_dirs_walk(&path, name, parent, walk, depth + 1, result);
             }
         }
     }
+}
+
+/// Recursively find git repositories.
+/// Finds repositories with a `.git` directory, worktrees and submodules
+/// with a `.git` file, repositories nested in other ones and bare repositories
+#[must_use]
+pub fn find_repos(dir: &Path, opts: &WalkOptions) -> Vec<PathBuf> {
+    let mut walk = Walk::new(*opts);
+    let mut result = Vec::new();
+    if walk.enter(dir, 0) {
+        find_repos_walk(dir, &mut walk, 0, &mut result);
+    }
     result
 }
 
+fn find_repos_walk(dir: &Path, walk: &mut Walk, depth: usize, result: &mut Vec<PathBuf>) {
+    if dir.join(".git").exists() {
+        result.push(dir.to_path_buf());
+    } else if is_bare_repo(dir) {
+        // Bare repositories have no working tree to search
+        result.push(dir.to_path_buf());
+        return;
+    }
+
+    if let Ok(entries) = fs::read_dir(dir) {
+        for entry in entries {
+            let entry = entry.expect("Error unpacking path");
+            let path = entry.path();
+            if entry.file_name() != ".git" && walk.enter(&path, depth + 1) {
+                find_repos_walk(&path, walk, depth + 1, result);
+            }
+        }
+    }
+}
+
+/// Check if directory looks like a bare git repository
+#[must_use]
+pub fn is_bare_repo(dir: &Path) -> bool {
+    dir.join("HEAD").is_file() && dir.join("objects").is_dir() && dir.join("refs").is_dir()
+}
+
 /// Recursively find files
 #[must_use]
 pub fn find_files(dir: &str, pattern: &str) -> Vec<PathBuf> {
-    let re = Regex::new(pattern).expect("Error compiling regex");
+    let selector = FileSelector::new(
+        Some(Regex::new(pattern).expect("Error compiling regex")),
+        &[],
+        &[],
+        &[],
+    );
+    find_files_by(Path::new(dir), &selector, &WalkOptions::default())
+}
+
+/// Recursively find files selected by a file selector.
+/// Paths are matched relative to root, `.git` directories
+/// and nested repositories are skipped
+#[must_use]
+pub fn find_files_by(root: &Path, selector: &FileSelector, opts: &WalkOptions) -> Vec<PathBuf> {
+    let mut walk = Walk::new(*opts);
     let mut found_files = Vec::new();
+    if walk.enter(root, 0) {
+        find_files_walk(root, root, selector, &mut walk, 0, &mut found_files);
+    }
+    found_files
+}
+
+fn find_files_walk(
+    root: &Path,
+    dir: &Path,
+    selector: &FileSelector,
+    walk: &mut Walk,
+    depth: usize,
+    found_files: &mut Vec<PathBuf>,
+) {
     let dir_entries = fs::read_dir(dir).expect("Error reading path");
 
     for entry in dir_entries {
         let entry = entry.expect("Error unwrapping directory entry");
         let path = entry.path();
-        let file_name = path
-            .file_name()
-            .expect("Error unwrapping file Path")
-            .to_str()
-            .expect("Error converting Path to str");
-        if path.is_file() && re.is_match(file_name) {
+        let relative = path
+            .strip_prefix(root)
+            .expect("Error stripping Path prefix");
+
+        if path.is_file() {
             // If path is a file and is a match
             // Push to found files
-            found_files.push(path.clone());
-        } else if path.is_dir() {
+            if selector.is_match(relative) && walk.accept_file(&path) {
+                found_files.push(path.clone());
+            }
+        } else if path.is_dir()
+            && entry.file_name() != ".git"
+            // Files of nested repositories and submodules belong to them
+            && !path.join(".git").exists()
+            && !selector.is_excluded(relative)
+            && walk.enter(&path, depth + 1)
+        {
             // Otherwise proceed to recursion
-            found_files.append(&mut find_files(
-                path.to_str().expect("Error converting Path to str"),
-                pattern,
-            ));
+            find_files_walk(root, &path, selector, walk, depth + 1, found_files);
         }
     }
-    found_files
+}
+
+/// Check if file looks binary by searching for a NUL byte in its first block
+pub fn is_binary(path: &Path) -> std::io::Result<bool> {
+    let mut buffer = [0; 8000];
+    let read = fs::File::open(path)?.read(&mut buffer)?;
+    Ok(buffer[..read].contains(&0))
+}
+
+/// Parse size with optional `K`, `M` or `G` suffix, i.e. `512K` or `10M`
+pub fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let (number, multiplier) = match size.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&size[..i], 1 << 10),
+        Some((i, 'M' | 'm')) => (&size[..i], 1 << 20),
+        Some((i, 'G' | 'g')) => (&size[..i], 1 << 30),
+        _ => (size, 1),
+    };
+    number
+        .trim()
+        .parse::<u64>()
+        .map(|n| n * multiplier)
+        .map_err(|_| format!("Invalid size '{size}'"))
+}
+
+/// Format seconds since epoch with a UTC offset in minutes,
+/// i.e. `2023-04-21 14:03:11 +0200`
+#[must_use]
+pub fn format_time(seconds: i64, offset: i32) -> String {
+    let local = seconds + i64::from(offset) * 60;
+    let (days, secs) = (local.div_euclid(86400), local.rem_euclid(86400));
+
+    // Convert days since epoch to civil date
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    let sign = if offset < 0 { '-' } else { '+' };
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02} {sign}{:02}{:02}",
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60,
+        offset.abs() / 60,
+        offset.abs() % 60
+    )
+}
+
+/// Extract first dotted version from a name for ordering, i.e. `release/1.10` after `release/1.9`.
+/// Pre-releases like `v2.0.0-rc1` order below the release, names without version below all
+#[must_use]
+pub fn version_key(name: &str) -> Option<(Vec<u64>, bool)> {
+    let re = Regex::new(r"\d+(?:\.\d+)*").expect("Error compiling regex");
+    let m = re.find(name)?;
+    let numbers = m
+        .as_str()
+        .split('.')
+        .map(|n| n.parse().unwrap_or(u64::MAX))
+        .collect();
+    let release = !name[m.end()..].starts_with('-');
+    Some((numbers, release))
+}
+
+/// Convert line endings to CRLF, leaving existing CRLF line endings intact
+#[must_use]
+pub fn to_crlf(contents: &str) -> String {
+    contents.replace("\r\n", "\n").replace('\n', "\r\n")
+}
+
+/// Replace lines by line number if line still has the expected content.
+/// Returns new contents and number of replacements that did not apply
+#[must_use]
+pub fn replace_lines(contents: &str, replacements: &[(usize, &str, &str)]) -> (String, usize) {
+    let mut result = String::with_capacity(contents.len());
+    let mut applied = 0;
+
+    for (n, line) in contents.split_inclusive('\n').enumerate() {
+        // Keep original line ending
+        let body = line.trim_end_matches('\n').trim_end_matches('\r');
+        let eol = &line[body.len()..];
+
+        match replacements
+            .iter()
+            .find(|(l, content, _)| *l == n && *content == body)
+        {
+            Some((_, _, replace)) => {
+                result.push_str(replace);
+                result.push_str(eol);
+                applied += 1;
+            }
+            None => result.push_str(line),
+        }
+    }
+    (result, replacements.len() - applied)
 }
 
 /// Prints info about paths
-pub fn paths_info_print(list: &Vec<PathBuf>, msg: &str, elements: usize) {
+pub fn paths_info_print(list: &[PathBuf], msg: &str, elements: usize) {
     println!("First {} ({}) {}:", elements, list.len(), msg);
     for f in 0..elements {
         if let Some(val) = list.get(f) {
@@ -72,3 +364,123 @@ pub fn paths_info_print(list: &Vec<PathBuf>, msg: &str, elements: usize) {
         }
     }
 }
+
+/// Run a shell command in a directory.
+/// Returns combined output as error if command fails
+pub fn run_command(cmd: &str, dir: &Path) -> Result<(), String> {
+    let output = Command::new("sh")
+        .args(["-c", cmd])
+        .current_dir(dir)
+        .output()
+        .map_err(|e| format!("Error running '{cmd}': {e}"))?;
+
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(format!(
+            "'{cmd}' failed with {}\n{}{}",
+            output.status,
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        ))
+    }
+}
+
+/// Substitute `{}` in a command with a shell quoted path.
+/// Path is appended if command has no placeholder
+#[must_use]
+pub fn substitute_path(cmd: &str, path: &Path) -> String {
+    let quoted = format!("'{}'", path.to_string_lossy().replace('\'', r"'\''"));
+    if cmd.contains("{}") {
+        cmd.replace("{}", &quoted)
+    } else {
+        format!("{cmd} {quoted}")
+    }
+}
+
+/// Check if path is an executable file
+#[must_use]
+pub fn is_executable(path: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::metadata(path).is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+    }
+    #[cfg(not(unix))]
+    {
+        path.is_file()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Find names of yaml files under root, relative to root and sorted
+    fn yaml_files(root: &Path, opts: WalkOptions) -> Vec<String> {
+        let selector = FileSelector::new(Some(Regex::new(r"\.yaml$").unwrap()), &[], &[], &[]);
+        let mut files: Vec<String> = find_files_by(root, &selector, &opts)
+            .iter()
+            .map(|f| f.strip_prefix(root).unwrap().display().to_string())
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn walk_symlinks_and_depth() {
+        use std::os::unix::fs::symlink;
+
+        let root = std::env::temp_dir().join(format!("gitraider-walk-{}", std::process::id()));
+        fs::remove_dir_all(&root).ok();
+        fs::create_dir_all(root.join("a/b/c")).unwrap();
+        fs::write(root.join("a/values.yaml"), "a").unwrap();
+        fs::write(root.join("a/b/c/deep.yaml"), "c").unwrap();
+        symlink("..", root.join("a/loop")).unwrap();
+        symlink("a/values.yaml", root.join("link.yaml")).unwrap();
+
+        // Symlinks are skipped by default
+        let files = yaml_files(&root, WalkOptions::default());
+        assert_eq!(files, vec!["a/b/c/deep.yaml", "a/values.yaml"]);
+
+        // Followed symlink loop is entered only once
+        let follow = WalkOptions {
+            max_depth: None,
+            follow_symlinks: true,
+        };
+        let files = yaml_files(&root, follow);
+        assert_eq!(files, vec!["a/b/c/deep.yaml", "a/values.yaml", "link.yaml"]);
+
+        let shallow = WalkOptions {
+            max_depth: Some(1),
+            follow_symlinks: false,
+        };
+        assert_eq!(yaml_files(&root, shallow), vec!["a/values.yaml"]);
+        let repos = find_dirs(&root, "values.yaml", &false, &shallow);
+        assert_eq!(repos, vec![root.join("a")]);
+        fs::remove_dir_all(root).ok();
+    }
+
+    #[test]
+    fn version_key_order() {
+        let mut names = vec![
+            "release/1.10",
+            "main",
+            "release/2.0-rc1",
+            "release/1.9",
+            "release/2.0",
+        ];
+        names.sort_by_key(|n| version_key(n));
+        assert_eq!(
+            names,
+            vec![
+                "main",
+                "release/1.9",
+                "release/1.10",
+                "release/2.0-rc1",
+                "release/2.0"
+            ]
+        );
+    }
+}
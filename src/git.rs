@@ -1,7 +1,15 @@
+use git2::build::CheckoutBuilder;
 use git2::{
-    Branch, BranchType, Branches, Commit, Cred, Oid, PushOptions, RemoteCallbacks, Repository,
+    AttrCheckFlags, AttrValue, Branch, BranchType, Branches, Commit, Cred, Direction, Oid,
+    PushOptions, RemoteCallbacks, Repository, Signature, Status, StatusOptions, WorktreeAddOptions,
+    WorktreePruneOptions,
 };
+use std::fs;
 use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::func;
+use crate::sign::Signer;
 
 /// Get repo from path
 pub fn get_repo(path: &PathBuf) -> Result<Repository, git2::Error> {
@@ -10,7 +18,7 @@ pub fn get_repo(path: &PathBuf) -> Result<Repository, git2::Error> {
 }
 
 /// Get all branches in a repo
-pub fn get_branches(repo: &Repository) -> Result<Branches, git2::Error> {
+pub fn get_branches(repo: &Repository) -> Result<Branches<'_>, git2::Error> {
     let branches = repo.branches(Some(BranchType::Local))?;
     Ok(branches)
 }
@@ -43,29 +51,160 @@ pub fn checkout_branch(repo: &Repository, branch: &Branch) -> Result<Oid, git2::
     let refname = get_ref(branch);
     println!("  Checking out {}", &refname);
 
-    let (object, reference) = repo.revparse_ext(refname).expect("    Object not found");
+    let (object, reference) = repo.revparse_ext(refname)?;
 
-    repo.checkout_tree(&object, None)
-        .expect("    Failed to checkout");
+    repo.checkout_tree(&object, None)?;
 
     match reference {
         // gref is an actual reference like branches or tags
         Some(gref) => repo.set_head(gref.name().expect("Error unwrapping refname")),
         // this is a commit, not a reference
         None => repo.set_head_detached(object.id()),
-    }
-    .expect("    Failed to set HEAD");
+    }?;
 
-    let head = repo
-        .head()
-        .expect("Error unwrapping repo head")
-        .target()
-        .expect("Error head target");
+    let head = repo.head()?.target().expect("Error head target");
     println!("  Success branch checkout '{refname}' {head}");
 
     Ok(head)
 }
 
+/// Get reference name of checked out branch, or commit id if HEAD is detached
+pub fn head_ref(repo: &Repository) -> Result<String, git2::Error> {
+    let head = repo.head()?;
+    let name = if repo.head_detached()? {
+        head.target().map(|oid| oid.to_string())
+    } else {
+        head.name().map(str::to_string)
+    };
+    name.ok_or_else(|| git2::Error::from_str("Invalid HEAD"))
+}
+
+/// Check out a reference name or commit id recorded by `head_ref`
+pub fn restore_head(repo: &Repository, head: &str) -> Result<(), git2::Error> {
+    let object = repo.revparse_single(head)?;
+    repo.checkout_tree(&object, None)?;
+    if head.starts_with("refs/") {
+        repo.set_head(head)
+    } else {
+        repo.set_head_detached(object.id())
+    }
+}
+
+/// Check out commit of a reference like `refs/tags/v1.0` with detached HEAD
+pub fn checkout_detached(repo: &Repository, refname: &str) -> Result<Oid, git2::Error> {
+    println!("  Checking out {refname} detached");
+    let commit = repo.revparse_single(refname)?.peel_to_commit()?;
+    repo.checkout_tree(commit.as_object(), None)?;
+    repo.set_head_detached(commit.id())?;
+    println!("  Success detached checkout '{refname}' {}", commit.id());
+    Ok(commit.id())
+}
+
+/// Add a linked worktree checking out a local branch,
+/// or a new branch named after the worktree without one
+pub fn add_worktree(
+    repo: &Repository,
+    name: &str,
+    path: &Path,
+    branch: Option<&Branch>,
+) -> Result<Repository, git2::Error> {
+    let mut opts = WorktreeAddOptions::new();
+    opts.reference(branch.map(Branch::get));
+    let worktree = repo.worktree(name, path, Some(&opts))?;
+    Repository::open_from_worktree(&worktree)
+}
+
+/// Remove a linked worktree with its files and the branch created for it, if any
+pub fn remove_worktree(repo: &Repository, name: &str) -> Result<(), git2::Error> {
+    repo.find_worktree(name)?.prune(Some(
+        WorktreePruneOptions::new().valid(true).working_tree(true),
+    ))?;
+    if let Ok(mut branch) = repo.find_branch(name, BranchType::Local) {
+        branch.delete()?;
+    }
+    Ok(())
+}
+
+/// Check if a branch is checked out in the main checkout or any linked worktree
+pub fn is_checked_out(repo: &Repository, refname: &str) -> Result<bool, git2::Error> {
+    let on_branch = |r: &Repository| r.head().is_ok_and(|h| h.name() == Some(refname));
+    let main = Repository::open(common_dir(repo))?;
+    if on_branch(&main) {
+        return Ok(true);
+    }
+    let names = main.worktrees()?;
+    Ok(names.iter().flatten().any(|name| {
+        main.find_worktree(name)
+            .and_then(|w| Repository::open_from_worktree(&w))
+            .is_ok_and(|r| on_branch(&r))
+    }))
+}
+
+/// Check if commit of a reference is or descends from a commit
+#[must_use]
+pub fn contains(repo: &Repository, refname: &str, oid: Oid) -> bool {
+    repo.refname_to_id(refname)
+        .is_ok_and(|id| id == oid || repo.graph_descendant_of(id, oid).unwrap_or(false))
+}
+
+/// Move a local branch forward to a commit unless it is checked out.
+/// Returns whether the branch contains the commit
+pub fn fast_forward(repo: &Repository, branch: &str, oid: Oid) -> Result<bool, git2::Error> {
+    let refname = format!("refs/heads/{branch}");
+    let mut reference = repo.find_reference(&refname)?;
+    if contains(repo, &refname, oid) {
+        return Ok(true);
+    }
+    let target = reference.peel_to_commit()?.id();
+    if !repo.graph_descendant_of(oid, target)? || is_checked_out(repo, &refname)? {
+        return Ok(false);
+    }
+    reference.set_target(oid, "gitraider: fast-forward")?;
+    println!("  Fast-forwarded {branch} to {oid}");
+    Ok(true)
+}
+
+/// Get name of the branch `HEAD` of a remote points to
+pub fn default_branch(repo: &Repository, remote: &str) -> Result<String, git2::Error> {
+    let head = repo.find_reference(&format!("refs/remotes/{remote}/HEAD"))?;
+    let target = head.symbolic_target().ok_or_else(|| {
+        git2::Error::from_str(&format!("{remote}/HEAD is not a symbolic reference"))
+    })?;
+    let prefix = format!("refs/remotes/{remote}/");
+    Ok(target.strip_prefix(&prefix).unwrap_or(target).to_string())
+}
+
+/// Find local branch, creating it from a remote with upstream set if it only exists there
+pub fn local_branch<'r>(
+    repo: &'r Repository,
+    remote: &str,
+    name: &str,
+) -> Result<Branch<'r>, git2::Error> {
+    if let Ok(branch) = repo.find_branch(name, BranchType::Local) {
+        return Ok(branch);
+    }
+    let upstream = format!("{remote}/{name}");
+    let commit = repo
+        .find_branch(&upstream, BranchType::Remote)?
+        .get()
+        .peel_to_commit()?;
+    let mut branch = repo.branch(name, &commit, false)?;
+    branch.set_upstream(Some(&upstream))?;
+    Ok(branch)
+}
+
+/// Get names of tags, without `refs/tags/` prefix
+pub fn get_tags(repo: &Repository) -> Result<Vec<String>, git2::Error> {
+    let tags = repo.tag_names(None)?;
+    Ok(tags.iter().flatten().map(str::to_string).collect())
+}
+
+/// Get commit time of a reference in seconds since epoch
+pub fn ref_time(repo: &Repository, refname: &str) -> Result<i64, git2::Error> {
+    let commit = repo.revparse_single(refname)?.peel_to_commit()?;
+    Ok(commit.time().seconds())
+}
+
 /// Stage all changes
 pub fn stage_all(repo: &mut Repository) -> Result<(), git2::Error> {
     let mut index = repo.index()?;
@@ -82,24 +221,357 @@ pub fn stage_file(repo: &mut Repository, file: &Path) -> Result<(), git2::Error>
     Ok(())
 }
 
-/// Commit staged changes
-pub fn commit(repo: &mut Repository, msg: &str) -> Result<(), git2::Error> {
+/// Stage new HEAD of a submodule in its superproject
+pub fn stage_submodule(repo: &mut Repository, path: &str) -> Result<(), git2::Error> {
+    let mut submodule = repo.find_submodule(path)?;
+    submodule.add_to_index(true)?;
+    Ok(())
+}
+
+/// Get paths of modified and untracked files relative to repository root
+pub fn changed_files(repo: &Repository) -> Result<Vec<PathBuf>, git2::Error> {
+    let mut opts = StatusOptions::new();
+    opts.include_untracked(true).recurse_untracked_dirs(true);
+
+    let changed = repo
+        .statuses(Some(&mut opts))?
+        .iter()
+        .filter(|s| {
+            s.status()
+                .intersects(Status::WT_MODIFIED | Status::WT_NEW | Status::WT_TYPECHANGE)
+        })
+        .filter_map(|s| s.path().map(PathBuf::from))
+        .collect();
+    Ok(changed)
+}
+
+/// Check if working tree or index has changes, including untracked files
+pub fn is_dirty(repo: &Repository) -> Result<bool, git2::Error> {
+    let mut opts = StatusOptions::new();
+    opts.include_untracked(true).include_ignored(false);
+    Ok(!repo.statuses(Some(&mut opts))?.is_empty())
+}
+
+/// Check if tracked files have changes in working tree or index
+pub fn has_tracked_changes(repo: &Repository) -> Result<bool, git2::Error> {
+    let mut opts = StatusOptions::new();
+    opts.include_untracked(false).include_ignored(false);
+    Ok(!repo.statuses(Some(&mut opts))?.is_empty())
+}
+
+/// Get status and working tree content id of each changed tracked file,
+/// content id is `None` for deleted files
+pub fn tracked_changes(
+    repo: &Repository,
+) -> Result<Vec<(PathBuf, Status, Option<Oid>)>, git2::Error> {
+    let workdir = repo
+        .workdir()
+        .ok_or_else(|| git2::Error::from_str("bare repository"))?;
+    let mut opts = StatusOptions::new();
+    opts.include_untracked(false).include_ignored(false);
+    let changes = repo
+        .statuses(Some(&mut opts))?
+        .iter()
+        .filter_map(|entry| {
+            let path = PathBuf::from(entry.path()?);
+            let id = Oid::hash_file(git2::ObjectType::Blob, workdir.join(&path)).ok();
+            Some((path, entry.status(), id))
+        })
+        .collect();
+    Ok(changes)
+}
+
+/// Get paths of files staged for commit relative to repository root
+pub fn staged_files(repo: &Repository) -> Result<Vec<PathBuf>, git2::Error> {
+    let head = repo.head().ok().map(|h| h.peel_to_tree()).transpose()?;
+    let diff = repo.diff_tree_to_index(head.as_ref(), None, None)?;
+    let staged = diff
+        .deltas()
+        .filter_map(|d| d.new_file().path().or_else(|| d.old_file().path()))
+        .map(Path::to_path_buf)
+        .collect();
+    Ok(staged)
+}
+
+/// Count commits of HEAD ahead and behind its upstream branch.
+/// Returns `None` if HEAD is detached or has no upstream
+pub fn ahead_behind(repo: &Repository) -> Result<Option<(usize, usize)>, git2::Error> {
+    let head = repo.head()?;
+    if !head.is_branch() {
+        return Ok(None);
+    }
+    let branch = Branch::wrap(head);
+    let Ok(upstream) = branch.upstream() else {
+        return Ok(None);
+    };
+    match (branch.get().target(), upstream.get().target()) {
+        (Some(local), Some(remote)) => repo.graph_ahead_behind(local, remote).map(Some),
+        _ => Ok(None),
+    }
+}
+
+/// Get a git attribute for a path relative to repository root.
+/// Returns `Some("true")` if set, `Some("false")` if unset and `None` if unspecified
+pub fn get_attr(repo: &Repository, path: &Path, name: &str) -> Result<Option<String>, git2::Error> {
+    let value = repo.get_attr(path, name, AttrCheckFlags::FILE_THEN_INDEX)?;
+    Ok(match AttrValue::from_string(value) {
+        AttrValue::True => Some("true".to_string()),
+        AttrValue::False => Some("false".to_string()),
+        AttrValue::String(s) => Some(s.to_string()),
+        AttrValue::Bytes(b) => Some(String::from_utf8_lossy(b).to_string()),
+        AttrValue::Unspecified => None,
+    })
+}
+
+/// Discard staged and working tree changes of specific files,
+/// removing files that are not in HEAD
+pub fn rollback(repo: &Repository, files: &[&Path]) -> Result<(), git2::Error> {
+    let head = repo.head()?.peel_to_commit()?;
+    repo.reset_default(Some(head.as_object()), files)?;
+
+    let tree = head.tree()?;
+    let workdir = repo.workdir().unwrap_or_else(|| repo.path());
+    let (tracked, untracked): (Vec<&Path>, Vec<&Path>) =
+        files.iter().partition(|f| tree.get_path(f).is_ok());
+    untracked.iter().for_each(|f| {
+        fs::remove_file(workdir.join(f)).ok();
+    });
+    if tracked.is_empty() {
+        return Ok(());
+    }
+
+    let mut checkout = CheckoutBuilder::new();
+    checkout.force();
+    tracked.iter().for_each(|f| {
+        checkout.path(f);
+    });
+    repo.checkout_head(Some(&mut checkout))?;
+    Ok(())
+}
+
+/// Get git directory shared by all worktrees of a repo
+#[must_use]
+pub fn common_dir(repo: &Repository) -> PathBuf {
+    // Linked worktrees point to the main repository's git directory
+    fs::read_to_string(repo.path().join("commondir")).map_or_else(
+        |_| repo.path().to_path_buf(),
+        |dir| repo.path().join(dir.trim()),
+    )
+}
+
+/// Restore working tree files from index, removing files that are not in it
+pub fn restore_files(repo: &Repository, files: &[&Path]) -> Result<(), git2::Error> {
+    let index = repo.index()?;
+    let workdir = repo.workdir().unwrap_or_else(|| repo.path());
+    let (tracked, untracked): (Vec<&Path>, Vec<&Path>) =
+        files.iter().partition(|f| index.get_path(f, 0).is_some());
+    untracked.iter().for_each(|f| {
+        fs::remove_file(workdir.join(f)).ok();
+    });
+    if tracked.is_empty() {
+        return Ok(());
+    }
+
+    let mut checkout = CheckoutBuilder::new();
+    checkout.force();
+    tracked.iter().for_each(|f| {
+        checkout.path(f);
+    });
+    repo.checkout_index(None, Some(&mut checkout))
+}
+
+/// File in git directory listing files staged by gitraider
+const STAGED_RECORD: &str = "gitraider-staged";
+
+/// Record files staged by gitraider, so that a later commit leaves other staged files alone
+pub fn record_staged(repo: &Repository, files: &[&Path]) -> std::io::Result<()> {
+    let mut recorded = recorded_staged(repo);
+    files.iter().for_each(|f| {
+        if !recorded.iter().any(|r| r == f) {
+            recorded.push(f.to_path_buf());
+        }
+    });
+    let lines: String = recorded
+        .iter()
+        .map(|f| format!("{}\n", f.display()))
+        .collect();
+    fs::write(repo.path().join(STAGED_RECORD), lines)
+}
+
+/// Get files recorded as staged by gitraider
+#[must_use]
+pub fn recorded_staged(repo: &Repository) -> Vec<PathBuf> {
+    fs::read_to_string(repo.path().join(STAGED_RECORD))
+        .unwrap_or_default()
+        .lines()
+        .map(PathBuf::from)
+        .collect()
+}
+
+/// Forget files recorded as staged by gitraider once they are committed
+pub fn clear_staged_record(repo: &Repository) {
+    fs::remove_file(repo.path().join(STAGED_RECORD)).ok();
+}
+
+/// Check if all commits not yet pushed to upstream were made by gitraider
+pub fn unpushed_are_raided(repo: &Repository) -> Result<bool, git2::Error> {
+    let head = repo.head()?;
+    let upstream = Branch::wrap(head).upstream()?;
+    let mut walk = repo.revwalk()?;
+    walk.push_head()?;
+    if let Some(oid) = upstream.get().target() {
+        walk.hide(oid)?;
+    }
+    for oid in walk {
+        if !has_trailer(&repo.find_commit(oid?)?) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Get directory containing repo's hooks, honouring `core.hooksPath`
+#[must_use]
+pub fn hooks_dir(repo: &Repository) -> PathBuf {
+    let workdir = repo.workdir().unwrap_or_else(|| repo.path());
+    repo.config()
+        .and_then(|c| c.get_path("core.hooksPath"))
+        .map_or_else(|_| common_dir(repo).join("hooks"), |p| workdir.join(p))
+}
+
+/// Run a repository hook if it exists and is executable.
+/// Returns hook's output as error if hook fails
+pub fn run_hook(repo: &Repository, name: &str, args: &[&Path]) -> Result<(), git2::Error> {
+    let hook = hooks_dir(repo).join(name);
+    if !func::is_executable(&hook) {
+        return Ok(());
+    }
+
+    println!("  Running {name} hook");
+    let workdir = repo.workdir().unwrap_or_else(|| repo.path());
+    let output = Command::new(&hook)
+        .args(args)
+        .current_dir(workdir)
+        .env("GIT_DIR", repo.path())
+        .output()
+        .map_err(|e| git2::Error::from_str(&format!("Error running {name} hook: {e}")))?;
+
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(git2::Error::from_str(&format!(
+            "{name} hook failed with {}\n{}{}",
+            output.status,
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        )))
+    }
+}
+
+/// Run `pre-commit` and `commit-msg` hooks for a commit message
+pub fn run_commit_hooks(repo: &Repository, msg: &str) -> Result<(), git2::Error> {
+    run_hook(repo, "pre-commit", &[])?;
+
+    // commit-msg hook receives a path to file with commit message
+    let msg_file = repo.path().join("COMMIT_EDITMSG");
+    fs::write(&msg_file, with_trailer(msg))
+        .map_err(|e| git2::Error::from_str(&format!("Error writing commit message: {e}")))?;
+    run_hook(repo, "commit-msg", &[&msg_file])
+}
+
+/// Trailer appended to every commit created by gitraider
+pub const TRAILER: &str = "Raided-by: gitraider";
+
+/// Append gitraider trailer to a commit message
+#[must_use]
+pub fn with_trailer(msg: &str) -> String {
+    format!("{}\n\n{TRAILER}\n", msg.trim_end())
+}
+
+/// Check if a commit was made by gitraider, either by its trailer
+/// or by its message matching the given one
+#[must_use]
+pub fn is_raider_commit(commit: &Commit, msg: &str) -> bool {
+    has_trailer(commit) || commit.message().unwrap_or_default().trim() == msg.trim()
+}
+
+/// Check if a commit has gitraider's trailer
+#[must_use]
+pub fn has_trailer(commit: &Commit) -> bool {
+    let commit_msg = commit.message().unwrap_or_default();
+    commit_msg.lines().any(|l| l.trim() == TRAILER)
+}
+
+/// Commit staged changes, signing the commit if a signer is given.
+/// Only given paths are committed, other staged changes stay in the index.
+/// In amend mode HEAD is replaced if it is a gitraider commit,
+/// returning id of the replaced commit
+pub fn commit(
+    repo: &mut Repository,
+    msg: &str,
+    paths: &[&Path],
+    author: Option<&Signature>,
+    signer: Option<&Signer>,
+    amend: bool,
+) -> Result<Option<Oid>, git2::Error> {
     // Gather git objects
-    let mut index = repo.index().expect("Error unwrapping repo index");
-    let oid = index.write_tree().expect("Error unwrapping index tree");
     let signature = repo.signature().expect("Error getting user's signature");
-    let parent_commit = get_last_commit(repo).expect("Error getting last commit");
+    let last_commit = get_last_commit(repo).expect("Error getting last commit");
+
+    // Build tree of last commit with staged state of given paths
+    let index = repo.index()?;
+    let mut tree_index = git2::Index::new()?;
+    tree_index.read_tree(&last_commit.tree()?)?;
+    for path in paths {
+        match index.get_path(path, 0) {
+            Some(entry) => tree_index.add(&entry)?,
+            None => tree_index.remove_path(path)?,
+        }
+    }
+    let oid = tree_index.write_tree_to(repo)?;
     let tree = repo.find_tree(oid).expect("Error unwrapping tree");
+    let full_msg = with_trailer(msg);
+
+    // When amending, fold changes into previous gitraider commit
+    // keeping its original author and parents
+    let amending = amend && is_raider_commit(&last_commit, msg);
+    let replaced = amending.then(|| last_commit.id());
+    if amend && !amending {
+        println!("    HEAD is not a gitraider commit, creating new commit");
+    }
+    let (author, parents) = if amending {
+        println!("    Amending commit {}", last_commit.id());
+        (
+            last_commit.author().to_owned(),
+            last_commit.parents().collect::<Vec<Commit>>(),
+        )
+    } else {
+        (author.unwrap_or(&signature).to_owned(), vec![last_commit])
+    };
+    let parents: Vec<&Commit> = parents.iter().collect();
 
     // Create new commit
-    repo.commit(
-        Some("HEAD"),
-        &signature,
-        &signature,
-        msg,
-        &tree,
-        &[&parent_commit],
-    )?;
+    let new_oid = if let Some(signer) = signer {
+        let buffer = repo.commit_create_buffer(&author, &signature, &full_msg, &tree, &parents)?;
+        let content = buffer
+            .as_str()
+            .ok_or_else(|| git2::Error::from_str("Commit buffer is not valid UTF-8"))?;
+        let committer = format!(
+            "{} <{}>",
+            signature.name().unwrap_or_default(),
+            signature.email().unwrap_or_default()
+        );
+        let commit_signature = signer.sign(content, &committer)?;
+        println!("    Signed commit with {:?} key", signer.format);
+        repo.commit_signed(content, &commit_signature, None)?
+    } else {
+        repo.commit(None, &author, &signature, &full_msg, &tree, &parents)?
+    };
+
+    // Commit is not attached to a ref, move HEAD manually
+    let action = if amending { "amend" } else { "commit" };
+    repo.head()?
+        .set_target(new_oid, &format!("{action} (gitraider): {msg}"))?;
 
     // Get new commit
     let new_commit = get_last_commit(repo).expect("Error getting new commit");
@@ -107,37 +579,84 @@ pub fn commit(repo: &mut Repository, msg: &str) -> Result<(), git2::Error> {
     let new_head = new_commit.id();
 
     // Check if commit message matches
-    if new_msg == msg {
-        println!("    Success commit '{new_msg}' {new_head}");
+    if new_msg == full_msg {
+        let summary = msg.lines().next().unwrap_or_default();
+        println!("    Success {action} '{summary}' {new_head}");
     } else {
         println!("    Warning, commit message mismatch '{new_msg}'");
     }
 
-    Ok(())
+    Ok(replaced)
 }
 
-/// Push changes to remote
-pub fn push(repo: &Repository, username: &str) -> Result<(), git2::Error> {
-    // Setup remote
-    let mut opts = PushOptions::default();
-    let mut callbacks = RemoteCallbacks::new();
+/// Parse signature given as `Name <email>`
+pub fn parse_signature(signature: &str) -> Result<Signature<'static>, git2::Error> {
+    let (name, email) = signature
+        .trim()
+        .strip_suffix('>')
+        .and_then(|s| s.split_once('<'))
+        .ok_or_else(|| {
+            git2::Error::from_str(&format!("'{signature}' is not in 'Name <email>' format"))
+        })?;
+    Signature::now(name.trim(), email.trim())
+}
 
-    // Configure authentication credentials
-    callbacks.credentials(|_url, username_from_url, _allowed_types| {
-        Cred::ssh_key_from_agent(username_from_url.unwrap_or(username))
-    });
+/// Get upstream commit if HEAD amends it, that is both are gitraider commits with the same parents
+pub fn amended_upstream(repo: &Repository) -> Option<Oid> {
+    let head = repo.head().ok()?;
+    let upstream = Branch::wrap(head)
+        .upstream()
+        .ok()?
+        .get()
+        .peel_to_commit()
+        .ok()?;
+    let local = repo.head().ok()?.peel_to_commit().ok()?;
+    let amends = local.id() != upstream.id()
+        && has_trailer(&local)
+        && has_trailer(&upstream)
+        && local.parent_ids().eq(upstream.parent_ids());
+    amends.then(|| upstream.id())
+}
 
-    // Set the callbacks for the remote
-    opts.remote_callbacks(callbacks);
+/// Push current branch to remote branch `dest`, by default one of the same name.
+/// If history was rewritten, force push
+/// only while remote branch is still at the leased commit that was replaced
+pub fn push(
+    repo: &Repository,
+    remote: &str,
+    username: &str,
+    dest: Option<&str>,
+    lease: Option<Oid>,
+) -> Result<(), git2::Error> {
+    let mut remote = repo.find_remote(remote)?;
+    let current_branch = get_branch_name(repo)?;
+    let local = format!("refs/heads/{current_branch}");
+    let current_branch = dest.unwrap_or(&current_branch);
+    let refname = format!("refs/heads/{current_branch}");
 
-    // Assume the remote's name is "origin"
-    let mut remote = repo.find_remote("origin")?;
+    // Check remote branch was not updated since the amended commit was pushed
+    if let Some(expected) = lease {
+        let connection = remote.connect_auth(Direction::Push, Some(callbacks(username)), None)?;
+        let actual = connection
+            .list()?
+            .iter()
+            .find(|h| h.name() == refname)
+            .map(git2::RemoteHead::oid);
+        if actual != Some(expected) {
+            return Err(git2::Error::from_str(&format!(
+                "remote {current_branch} is not at amended commit {expected}, refusing to force push"
+            )));
+        }
+    }
+
+    // Setup remote
+    let mut opts = PushOptions::default();
+    opts.remote_callbacks(callbacks(username));
 
     // Push changes of the current checkout branch
-    let current_branch = get_branch_name(repo).expect("Error getting branch");
     let refspec = format!(
-        "refs/heads/{}:refs/heads/{}",
-        current_branch, current_branch
+        "{}{local}:{refname}",
+        if lease.is_some() { "+" } else { "" }
     );
 
     // Push changes based on settings
@@ -146,11 +665,169 @@ pub fn push(repo: &Repository, username: &str) -> Result<(), git2::Error> {
     Ok(())
 }
 
+/// Callbacks authenticating with SSH agent
+fn callbacks(username: &str) -> RemoteCallbacks<'_> {
+    let mut callbacks = RemoteCallbacks::new();
+    callbacks.credentials(|_url, username_from_url, _allowed_types| {
+        Cred::ssh_key_from_agent(username_from_url.unwrap_or(username))
+    });
+    callbacks
+}
+
 /// Get last commit
-fn get_last_commit(repo: &Repository) -> Result<Commit, git2::Error> {
+fn get_last_commit(repo: &Repository) -> Result<Commit<'_>, git2::Error> {
     let commit = repo
         .head()
         .expect("Error unwrapping commit from head")
         .peel_to_commit()?;
     Ok(commit)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Create a repository with an initial commit of `values.yaml` in a fresh directory
+    fn scratch_repo(name: &str) -> (PathBuf, Repository) {
+        let root =
+            std::env::temp_dir().join(format!("gitraider-git-{name}-{}", std::process::id()));
+        fs::remove_dir_all(&root).ok();
+        let repo = Repository::init(root.join("repo")).unwrap();
+        let mut config = repo.config().unwrap();
+        config.set_str("user.name", "Test").unwrap();
+        config.set_str("user.email", "test@example.com").unwrap();
+        write_file(&repo, "values.yaml", "host: prod\n");
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("values.yaml")).unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = repo.signature().unwrap();
+        repo.commit(Some("HEAD"), &signature, &signature, "init", &tree, &[])
+            .unwrap();
+        drop(tree);
+        (root, repo)
+    }
+
+    fn write_file(repo: &Repository, file: &str, content: &str) {
+        fs::write(repo.workdir().unwrap().join(file), content).unwrap();
+    }
+
+    /// Write and stage a file, then commit it with gitraider
+    fn raid_commit(repo: &mut Repository, content: &str, amend: bool) -> Option<Oid> {
+        write_file(repo, "values.yaml", content);
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("values.yaml")).unwrap();
+        index.write().unwrap();
+        commit(
+            repo,
+            "Use stage",
+            &[Path::new("values.yaml")],
+            None,
+            None,
+            amend,
+        )
+        .unwrap()
+    }
+
+    fn head(repo: &Repository) -> Commit<'_> {
+        repo.head().unwrap().peel_to_commit().unwrap()
+    }
+
+    #[test]
+    fn commit_amend() {
+        let (root, mut repo) = scratch_repo("amend");
+        let init = head(&repo).id();
+
+        // HEAD is not a gitraider commit, a new commit is created instead
+        assert_eq!(raid_commit(&mut repo, "host: stage\n", true), None);
+        assert!(has_trailer(&head(&repo)));
+        assert_eq!(head(&repo).parent_ids().collect::<Vec<_>>(), vec![init]);
+        let first = head(&repo).id();
+
+        // Gitraider commit is replaced, keeping its parents
+        assert_eq!(raid_commit(&mut repo, "host: test\n", true), Some(first));
+        let amended = head(&repo).id();
+        assert_ne!(amended, first);
+        assert_eq!(head(&repo).parent_ids().collect::<Vec<_>>(), vec![init]);
+        let tree = head(&repo).tree().unwrap();
+        let blob = tree.get_name("values.yaml").unwrap().id();
+        assert_eq!(repo.find_blob(blob).unwrap().content(), b"host: test\n");
+        drop(tree);
+
+        // Without amend mode a commit on top is created
+        assert_eq!(raid_commit(&mut repo, "host: dev\n", false), None);
+        assert_eq!(head(&repo).parent_id(0).unwrap(), amended);
+        fs::remove_dir_all(root).ok();
+    }
+
+    #[test]
+    fn amended_upstream_parents() {
+        let (root, mut repo) = scratch_repo("upstream");
+        let origin = root.join("origin.git");
+        Repository::init_bare(&origin).unwrap();
+        repo.remote("origin", origin.to_str().unwrap()).unwrap();
+        let branch = get_branch_name(&repo).unwrap();
+        let upstream = format!("refs/remotes/origin/{branch}");
+        let mut config = repo.config().unwrap();
+        config
+            .set_str(&format!("branch.{branch}.remote"), "origin")
+            .unwrap();
+        config
+            .set_str(
+                &format!("branch.{branch}.merge"),
+                &format!("refs/heads/{branch}"),
+            )
+            .unwrap();
+
+        // Upstream is not a gitraider commit
+        let init = head(&repo).id();
+        repo.reference(&upstream, init, true, "test").unwrap();
+        raid_commit(&mut repo, "host: stage\n", false);
+        assert_eq!(amended_upstream(&repo), None);
+
+        // Upstream has the gitraider commit that was amended
+        let pushed = head(&repo).id();
+        repo.reference(&upstream, pushed, true, "test").unwrap();
+        assert_eq!(amended_upstream(&repo), None);
+        raid_commit(&mut repo, "host: test\n", true);
+        assert_eq!(amended_upstream(&repo), Some(pushed));
+
+        // Upstream gitraider commit has other parents than the amended one
+        raid_commit(&mut repo, "host: dev\n", false);
+        let later = head(&repo).id();
+        repo.reference(&upstream, later, true, "test").unwrap();
+        raid_commit(&mut repo, "host: qa\n", false);
+        repo.head().unwrap().set_target(pushed, "test").unwrap();
+        raid_commit(&mut repo, "host: uat\n", true);
+        assert_eq!(amended_upstream(&repo), None);
+        drop(config);
+        fs::remove_dir_all(root).ok();
+    }
+
+    #[test]
+    fn push_lease() {
+        let (root, mut repo) = scratch_repo("lease");
+        let origin = root.join("origin.git");
+        let bare = Repository::init_bare(&origin).unwrap();
+        repo.remote("origin", origin.to_str().unwrap()).unwrap();
+        let branch = get_branch_name(&repo).unwrap();
+        let refname = format!("refs/heads/{branch}");
+
+        raid_commit(&mut repo, "host: stage\n", false);
+        push(&repo, "origin", "git", None, None).unwrap();
+        let pushed = head(&repo).id();
+        assert_eq!(bare.refname_to_id(&refname).unwrap(), pushed);
+
+        // Force push replaces the leased commit on remote
+        raid_commit(&mut repo, "host: test\n", true);
+        push(&repo, "origin", "git", None, Some(pushed)).unwrap();
+        let amended = head(&repo).id();
+        assert_eq!(bare.refname_to_id(&refname).unwrap(), amended);
+
+        // Remote moved on since, force push is refused
+        raid_commit(&mut repo, "host: dev\n", true);
+        assert!(push(&repo, "origin", "git", None, Some(pushed)).is_err());
+        assert_eq!(bare.refname_to_id(&refname).unwrap(), amended);
+        fs::remove_dir_all(root).ok();
+    }
+}
@@ -1,5 +1,12 @@
 pub mod config;
+pub mod filter;
 pub mod func;
 pub mod git;
+pub mod plan;
 pub mod raider;
+pub mod review;
+pub mod sign;
 pub mod structs;
+pub mod structured;
+pub mod template;
+pub mod tui;
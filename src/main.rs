@@ -1,68 +1,478 @@
+use std::io::{self, IsTerminal, Write};
+use std::path::Path;
 use std::time::Instant;
 
-use clap::Parser;
-use gitraider::config::Config;
-use gitraider::raider::RepoRaider;
+use gitraider::config::{Command, Config, ConfigCommand, OutputFormat, Setting};
+use gitraider::filter::{FileSelector, LineFilter, RepoFilter};
+use gitraider::func::{build_regex, PatternOptions, WalkOptions};
+use gitraider::plan::Plan;
+use gitraider::raider::{RepoRaider, TAG_PREFIX};
+use gitraider::review;
+use gitraider::structs::{Page, RunTotals};
+use gitraider::tui::{self, Action};
 
 fn main() {
-    let conf = Config::parse();
+    let (conf, settings) = Config::load();
     let start: Instant = Instant::now();
 
+    match &conf.command {
+        Some(Command::Search) => search(&conf),
+        Some(Command::Replace) => replace(&conf),
+        Some(Command::Commit) => commit(&conf),
+        Some(Command::Push) => push(&conf),
+        Some(Command::Status { format }) => status(&conf, *format),
+        Some(Command::ApplyPlan { file }) => apply_plan(&conf, file),
+        Some(Command::Config {
+            action: ConfigCommand::Show,
+        }) => show_config(&settings),
+        Some(Command::Browse | Command::Plan { .. }) | None => run(&conf),
+    }
+
+    // Keep JSON output machine readable
+    if !matches!(
+        conf.command,
+        Some(
+            Command::Status {
+                format: OutputFormat::Json
+            } | Command::Config { .. }
+        )
+    ) {
+        println!("Elapsed: {:.3?}", start.elapsed());
+    }
+}
+
+/// Run whole pipeline as configured by flags
+fn run(conf: &Config) {
+    // Tags are checked out with detached HEAD, commits on them would be left dangling
+    let tags = conf.branches().is_some_and(|b| b.starts_with(TAG_PREFIX));
+    if tags && (conf.commit.is_some() || conf.push) {
+        panic!("ERROR: Tags are checked out with detached HEAD, commit and push need branches selected with --branch");
+    }
+
+    let mut raider = discover(conf, conf.dry_run);
+    let planning = matches!(conf.command, Some(Command::Plan { .. }));
+    let mut plan = Plan::new(raider.path.clone(), conf.commit.as_deref(), conf.push);
+
+    // Select branches to change, each matching branch in turn
+    raider.select_branches(conf.branches(), conf.branch_pick);
+    if raider.max_branches() > 1 && conf.commit.is_none() && !conf.dry_run && !planning {
+        panic!("ERROR: Several branches matched, commit message must be specified to change each of them, or select one with --branch @current");
+    }
+
+    // Without review, changes of all branches are found before writing any file,
+    // so that limits are checked and confirmation is asked once for the whole run
+    let browsing = conf.command == Some(Command::Browse);
+    let reviewing = conf.interactive || browsing;
+    let surveyed = !reviewing && !planning && raider.max_branches() > 1;
+    if surveyed {
+        let mut totals = RunTotals::default();
+        while raider.next_branch() {
+            find_changes(conf, &mut raider);
+            raider.count_changes(&mut totals, &conf.limits());
+        }
+        check_limits(conf, &totals, false);
+        if !confirmed(conf, &totals, false) {
+            println!("Aborted, no files were written");
+            return;
+        }
+        raider.dirs.iter_mut().for_each(|d| d.history.clear());
+        raider.select_branches(conf.branches(), conf.branch_pick);
+    }
+
+    let mut totals = RunTotals::default();
+    let mut written = false;
+    while raider.next_branch() {
+        find_changes(conf, &mut raider);
+
+        // Review proposed replacements one by one
+        if conf.interactive {
+            review::review(&mut raider);
+        }
+
+        // Browse matches and choose what to do with selected ones
+        let (commit, push) = if browsing {
+            match tui::browse(
+                &mut raider,
+                conf.commit.is_some() && !tags,
+                conf.username.is_some(),
+            ) {
+                Action::Quit => {
+                    println!("Quit, {}", not_written(written));
+                    return;
+                }
+                action => (action >= Action::Commit, action == Action::Push),
+            }
+        } else {
+            (conf.commit.is_some(), conf.push)
+        };
+
+        if !surveyed {
+            raider.count_changes(&mut totals, &conf.limits());
+            check_limits(conf, &totals, written);
+        }
+
+        // Add changes to plan for review instead of applying them
+        if planning {
+            plan.add(&raider);
+            continue;
+        }
+
+        if !surveyed && !confirmed(conf, &totals, reviewing) {
+            println!("Aborted, {}", not_written(written));
+            return;
+        }
+
+        // Apply replace patterns to files
+        raider.apply();
+        written = true;
+
+        // Stage matches
+        raider.stage();
+
+        // Run hooks and validation command, rolling back repos that fail
+        if conf.hooks || conf.check.is_some() {
+            raider.validate(conf.hooks, conf.check.as_deref(), conf.commit.as_deref());
+        }
+
+        // Commit changes with message
+        if commit {
+            commit_changes(conf, &mut raider);
+
+            // If push flag is set, push to remote
+            if push {
+                push_changes(conf, &raider);
+            }
+        }
+    }
+
+    if let Some(Command::Plan { output }) = &conf.command {
+        plan.write(output);
+        println!("Wrote plan to {}", output.display());
+    }
+
+    // Print assessment for found Directories, Pages and Matches
+    if conf.assess {
+        assessment(&raider);
+    }
+}
+
+/// Report matches and proposed replacements without changing files
+fn search(conf: &Config) {
+    let mut raider = discover(conf, true);
+    // Current branch is read in place, without a worktree
+    raider.worktree &= conf.branches().is_some();
+    raider.select_branches(conf.branches(), conf.branch_pick);
+    while raider.next_branch() {
+        find_changes(conf, &mut raider);
+    }
+    assessment(&raider);
+}
+
+/// Edit matched files and stage them
+fn replace(conf: &Config) {
+    let mut raider = discover(conf, conf.dry_run);
+    raider.select_branches(conf.branches(), conf.branch_pick);
+    if raider.max_branches() > 1 {
+        panic!("ERROR: Several branches matched, replace changes one branch per repository, select one with --branch @current");
+    }
+    while raider.next_branch() {
+        find_changes(conf, &mut raider);
+        if conf.interactive {
+            review::review(&mut raider);
+        }
+        let mut totals = RunTotals::default();
+        raider.count_changes(&mut totals, &conf.limits());
+        check_limits(conf, &totals, false);
+        if !confirmed(conf, &totals, conf.interactive) {
+            println!("Aborted, no files were written");
+            return;
+        }
+        raider.apply();
+        raider.stage();
+    }
+    if conf.assess {
+        assessment(&raider);
+    }
+}
+
+/// Validate and commit changes staged in each repository
+fn commit(conf: &Config) {
+    let mut raider = discover(conf, conf.dry_run);
+    raider.find_staged();
+    if conf.hooks || conf.check.is_some() {
+        raider.validate(conf.hooks, conf.check.as_deref(), conf.commit.as_deref());
+    }
+    commit_changes(conf, &mut raider);
+    if conf.assess {
+        assessment(&raider);
+    }
+}
+
+/// Push repositories with commits not yet pushed to upstream
+fn push(conf: &Config) {
+    let mut raider = discover(conf, conf.dry_run);
+    raider.retain_unpushed();
+    push_changes(conf, &raider);
+}
+
+/// Print state of all repositories as a table or JSON
+fn status(conf: &Config, format: OutputFormat) {
+    let raider = discover(conf, true);
+    let statuses = raider.status();
+    if format == OutputFormat::Json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&statuses).expect("Error serializing status")
+        );
+        return;
+    }
+
+    let rows: Vec<[String; 6]> = statuses
+        .iter()
+        .map(|s| {
+            [
+                s.path.display().to_string(),
+                if s.detached {
+                    format!("({} detached)", s.branch)
+                } else {
+                    s.branch.clone()
+                },
+                if s.dirty { "dirty" } else { "clean" }.to_string(),
+                match (s.ahead, s.behind) {
+                    (Some(ahead), Some(behind)) => format!("+{ahead} -{behind}"),
+                    _ => "-".to_string(),
+                },
+                s.last_commit_date.clone().unwrap_or_default(),
+                s.last_commit_author.clone().unwrap_or_default(),
+            ]
+        })
+        .collect();
+    let header = [
+        "REPOSITORY",
+        "BRANCH",
+        "STATE",
+        "UPSTREAM",
+        "LAST COMMIT",
+        "AUTHOR",
+    ]
+    .map(str::to_string);
+    let widths: Vec<usize> = (0..header.len())
+        .map(|i| {
+            std::iter::once(&header)
+                .chain(&rows)
+                .map(|r| r[i].chars().count())
+                .max()
+                .unwrap_or_default()
+        })
+        .collect();
+    for row in std::iter::once(&header).chain(&rows) {
+        let line: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    }
+}
+
+/// Print effective configuration with the source of each value
+fn show_config(settings: &[Setting]) {
+    let width = settings
+        .iter()
+        .map(|s| s.name.len())
+        .max()
+        .unwrap_or_default();
+    for setting in settings {
+        println!(
+            "{:width$} = {:?}  # {}",
+            setting.name, setting.value, setting.source
+        );
+    }
+}
+
+/// Execute a reviewed plan instead of searching repositories
+fn apply_plan(conf: &Config, file: &Path) {
+    let plan = Plan::read(file);
+    if plan.push && conf.username.is_none() {
+        panic!("ERROR: Git username must be specified for push");
+    }
+    plan.execute(
+        file,
+        conf.dry_run,
+        &|raider| configure(conf, raider),
+        &conf.sign_options(),
+        conf.username.as_deref(),
+    );
+}
+
+/// Recursively find git repositories and filter them
+fn discover(conf: &Config, dry_run: bool) -> RepoRaider {
     // Recursively find directories that are git repositories
-    let mut raider = RepoRaider::new(conf.path, conf.dry_run);
+    let mut raider = RepoRaider::new(conf.path.clone(), dry_run);
+    configure(conf, &mut raider);
     raider.find_repos();
 
-    // Check out branch that matches regex pattern
-    raider.checkout_branch(conf.branch_pattern.as_str());
+    // Filter repositories by path, remote, file presence and repository list
+    let repo_filter = RepoFilter::new(
+        conf.repo_pattern.as_deref(),
+        conf.remote_url_pattern.as_deref(),
+        &conf.has_files,
+        conf.repo_list.as_deref(),
+    );
+    if !repo_filter.is_empty() {
+        raider.filter_repos(&repo_filter);
+    }
+    raider
+}
 
-    // Match files with regex pattern
-    if let Some(file_pattern) = conf.file_pattern {
-        raider.match_files(file_pattern.as_str());
+/// Apply raider settings from configuration
+fn configure(conf: &Config, raider: &mut RepoRaider) {
+    raider.submodules = conf.submodules;
+    raider.stay = conf.stay;
+    raider.worktree = conf.worktree;
+    raider.remote = conf.remote.clone();
+    raider.author = conf.author.clone();
+    raider.walk = WalkOptions {
+        max_depth: conf.max_depth,
+        follow_symlinks: conf.follow_symlinks,
+    };
+    raider.max_file_size = conf.max_file_size;
+    raider.patterns = PatternOptions {
+        fixed: conf.fixed_strings,
+        ignore_case: conf.ignore_case,
+        word: conf.word_regexp,
+    };
+}
+
+/// Match files and lines on current branches and prepare changes
+fn find_changes(conf: &Config, raider: &mut RepoRaider) {
+    // Match files with regex pattern, path patterns and globs
+    let selector = FileSelector::new(
+        conf.file_pattern
+            .as_deref()
+            .map(|p| build_regex(p, &raider.patterns)),
+        &conf.path_patterns,
+        &conf.include,
+        &conf.exclude,
+    );
+    if !selector.is_empty() {
+        raider.match_files_by(&selector);
     }
 
     // Match lines in files that match regex pattern
-    if let Some(content_pattern) = conf.line_pattern {
-        raider.match_lines(content_pattern.as_str());
+    if let Some(content_pattern) = &conf.line_pattern {
+        let mut line_filter = LineFilter::new(build_regex(content_pattern, &raider.patterns));
+        line_filter.not_lines = conf
+            .not_line_patterns
+            .iter()
+            .map(|p| build_regex(p, &raider.patterns))
+            .collect();
+        line_filter.near = conf
+            .near_pattern
+            .as_deref()
+            .map(|p| (build_regex(p, &raider.patterns), conf.near_lines));
+        line_filter.section = conf
+            .section_pattern
+            .as_deref()
+            .map(|p| build_regex(p, &raider.patterns));
+        raider.match_lines_by(&line_filter);
+    }
+
+    // Edit values at key path in structured files
+    if let Some(key_path) = &conf.key_path {
+        let value = conf
+            .value
+            .as_deref()
+            .unwrap_or_else(|| panic!("ERROR: No value flag specified"));
+        raider.edit_keys(key_path, value);
     }
 
     // Create replace patterns for each pattern
-    if let Some(select) = conf.line_select_pattern {
-        conf.line_replace_pattern.map_or_else(
+    if let Some(select) = &conf.line_select_pattern {
+        conf.line_replace_pattern.as_ref().map_or_else(
             || {
                 panic!("ERROR: No replace flag specified");
             },
             |replace| {
-                raider.replace(select.as_str(), replace.as_str());
+                raider.replace(
+                    select.as_str(),
+                    replace.as_str(),
+                    conf.literal_replace || conf.fixed_strings,
+                );
             },
         );
     }
 
-    // Apply replace patterns to files
-    raider.apply();
+    // Transform files with a command
+    if let Some(cmd) = &conf.exec {
+        raider.exec(cmd, conf.exec_root, conf.line_pattern.is_some());
+    }
+}
 
-    // Stage matches
-    raider.stage();
+/// Abort before writing more files if limits are exceeded by changes counted so far
+fn check_limits(conf: &Config, totals: &RunTotals, written: bool) {
+    if let Err(exceeded) = totals.check(&conf.limits()) {
+        exceeded.iter().for_each(|e| println!("  {e}"));
+        panic!("ERROR: Change limits exceeded, {}", not_written(written));
+    }
+}
 
-    // Commit changes with message
-    if let Some(commit_message) = conf.commit {
-        raider.commit(commit_message.as_str());
+/// Describe which files were left unwritten when stopping
+const fn not_written(written: bool) -> &'static str {
+    if written {
+        "files of earlier branches were already written"
+    } else {
+        "no files were written"
+    }
+}
 
-        // If push flag is set, push to remote
-        if conf.push && conf.username.is_some() {
-            raider.remote_push(conf.username.expect("Error unwrapping username").as_str());
+/// Ask for confirmation before writing files, unless reviewed one by one
+fn confirmed(conf: &Config, totals: &RunTotals, reviewed: bool) -> bool {
+    conf.dry_run
+        || conf.yes
+        || reviewed
+        || totals.files == 0
+        || confirm(totals.repos.len(), totals.files, totals.lines)
+}
+
+/// Commit changes with message, updating superprojects of changed submodules
+fn commit_changes(conf: &Config, raider: &mut RepoRaider) {
+    let message = conf
+        .commit
+        .as_deref()
+        .unwrap_or_else(|| panic!("ERROR: No commit message specified"));
+    let sign_opts = conf.sign_options();
+    raider.commit(message, &sign_opts, conf.amend);
+    if conf.submodules {
+        raider.update_gitlinks(message, &sign_opts);
+    }
+}
+
+/// Push to remote, overwriting remote branch where a commit was amended
+fn push_changes(conf: &Config, raider: &RepoRaider) {
+    match &conf.username {
+        Some(username) => {
+            if !raider.remote_push(username.as_str()) {
+                panic!("ERROR: Pushing failed for some repositories");
+            }
         }
         // If username or password was not set then throw an error
-        else if conf.push {
-            panic!("ERROR: Git username must be specified for push");
-        }
+        None => panic!("ERROR: Git username must be specified for push"),
     }
+}
 
-    // Print assessment for found Directories, Pages and Matches
-    if conf.assess {
-        assessment(&raider);
+/// Ask user to confirm writing changes, reading answer from stdin
+fn confirm(repos: usize, files: usize, lines: usize) -> bool {
+    if !io::stdin().is_terminal() {
+        panic!("ERROR: Cannot ask for confirmation without a terminal, use --yes to write changes");
     }
-
-    println!("Elapsed: {:.3?}", start.elapsed());
+    print!("Change {lines} lines in {files} files across {repos} repositories? [y/N] ");
+    io::stdout().flush().expect("Error flushing stdout");
+    let mut answer = String::new();
+    io::stdin()
+        .read_line(&mut answer)
+        .expect("Error reading answer");
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
 }
 
 /// Print assessment
@@ -73,24 +483,71 @@ fn assessment(raider: &RepoRaider) {
     println!("  Ln: - Original line, line number");
     println!("  Rn: - Replace line (if present), line number");
     println!("GIT REPOSITORIES");
+    let has_matches = |pages: &[Page]| pages.iter().any(|p| !p.matches.is_empty() || p.changes);
     for dir in &raider.dirs {
-        if dir.pages.iter().any(|p| !p.matches.is_empty()) {
-            let branch_name =
+        // Results of each processed branch, followed by pending changes
+        let history: Vec<_> = dir
+            .history
+            .iter()
+            .filter(|r| has_matches(&r.pages) || r.failed.is_some())
+            .collect();
+        let pending = has_matches(&dir.pages) || dir.failed.is_some();
+        if history.is_empty() && !pending {
+            continue;
+        }
+
+        println!("\nRepository: {}", dir.relative_path.display());
+        for result in history {
+            print_branch(
+                &result.branch,
+                &result.pages,
+                result.skipped_summary(),
+                result.failed.as_deref(),
+            );
+        }
+        if pending {
+            let branch_name = dir.branch.clone().unwrap_or_else(|| {
                 gitraider::git::get_branch_name(dir.repo.as_ref().expect("Folder not a git repo"))
-                    .expect("Error getting repo branch name");
-            println!("\nRepository: {}", dir.relative_path.display());
-            println!("Branch: {branch_name}");
-            dir.pages.iter().for_each(|p| {
-                println!("  F{}: {}", p.matches.len(), p.relative_path.display());
-                // Loop through matches
-                p.matches.iter().for_each(|m| {
-                    println!("    L{}: {}", m.line, m.content);
-                    // Print replace line if Some
-                    if let Some(r) = &m.replace {
-                        println!("    R{}: {}", m.line, r);
-                    }
-                });
+                    .expect("Error getting repo branch name")
             });
+            print_branch(
+                &branch_name,
+                &dir.pages,
+                dir.skipped_summary(),
+                dir.failed.as_deref(),
+            );
         }
     }
+
+    if !raider.skipped.is_empty() {
+        println!("\nSKIPPED REPOSITORIES");
+        for (path, reason) in &raider.skipped {
+            println!("  {}: {reason}", path.display());
+        }
+    }
+}
+
+/// Print matched files and lines of a branch
+fn print_branch(branch_name: &str, pages: &[Page], skipped: Option<String>, failed: Option<&str>) {
+    println!("Branch: {branch_name}");
+    if let Some(summary) = skipped {
+        println!("Skipped: {summary} files");
+    }
+    if let Some(reason) = failed {
+        println!("Failed: {}", reason.lines().next().unwrap_or_default());
+    }
+    pages.iter().for_each(|p| {
+        println!("  F{}: {}", p.matches.len(), p.relative_path.display());
+        if p.changes && p.matches.iter().all(|m| m.replace.is_none()) {
+            println!("    Changed by command");
+        }
+        // Loop through matches
+        p.matches.iter().for_each(|m| {
+            println!("    L{}: {}", m.line, m.content);
+            // Print replace line if Some
+            if let Some(r) = &m.replace {
+                println!("    R{}: {}", m.line, r);
+            }
+        });
+    });
 }
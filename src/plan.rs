@@ -0,0 +1,416 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::git;
+use crate::raider::RepoRaider;
+use crate::sign::SignOptions;
+use crate::structs::{Directory, Match, Page};
+
+/// Reviewable plan of all changes of a run
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Plan {
+    /// Absolute path repositories were searched in
+    pub root: PathBuf,
+    pub commit: Option<String>,
+    pub push: bool,
+    pub repos: Vec<RepoPlan>,
+}
+
+/// Planned changes of a repository
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RepoPlan {
+    /// Path relative to root
+    pub path: PathBuf,
+    pub branch: String,
+    pub files: Vec<FilePlan>,
+}
+
+/// Planned changes of a file
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FilePlan {
+    /// Path relative to repository root
+    pub path: PathBuf,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub changes: Vec<LineChange>,
+    /// New file contents produced by a command
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub crlf: bool,
+}
+
+/// Planned replacement of a line
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LineChange {
+    pub line: usize,
+    pub content: String,
+    pub replace: String,
+}
+
+/// Steps recorded in journal for each repository
+const APPLIED: &str = "applied";
+const COMMITTED: &str = "committed";
+const PUSHED: &str = "pushed";
+
+/// Journal of completed steps per repository and branch, stored next to plan file
+struct Journal {
+    path: PathBuf,
+    entries: Vec<(String, String, PathBuf)>,
+    dry_run: bool,
+}
+
+impl Journal {
+    fn path(plan_path: &Path) -> PathBuf {
+        let mut path = plan_path.as_os_str().to_owned();
+        path.push(".journal");
+        PathBuf::from(path)
+    }
+
+    fn open(plan_path: &Path, dry_run: bool) -> Self {
+        let path = Self::path(plan_path);
+        let entries = fs::read_to_string(&path)
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|l| {
+                let (step, rest) = l.split_once(' ')?;
+                let (branch, repo) = rest.split_once(' ')?;
+                Some((step.to_string(), branch.to_string(), PathBuf::from(repo)))
+            })
+            .collect();
+        Self {
+            path,
+            entries,
+            dry_run,
+        }
+    }
+
+    fn is_done(&self, repo: &RepoPlan, step: &str) -> bool {
+        self.entries
+            .iter()
+            .any(|(s, b, r)| s == step && *b == repo.branch && *r == repo.path)
+    }
+
+    fn record(&mut self, repo: &RepoPlan, step: &str) {
+        if self.dry_run {
+            return;
+        }
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .expect("Error opening journal");
+        writeln!(file, "{step} {} {}", repo.branch, repo.path.display())
+            .expect("Error writing journal");
+        self.entries
+            .push((step.to_string(), repo.branch.clone(), repo.path.clone()));
+    }
+}
+
+impl Plan {
+    /// Create empty plan for repositories under root
+    #[must_use]
+    pub fn new(root: PathBuf, commit: Option<&str>, push: bool) -> Self {
+        Self {
+            root,
+            commit: commit.map(str::to_string),
+            push,
+            repos: Vec::new(),
+        }
+    }
+
+    /// Add changes proposed by a raider on currently checked out branches
+    pub fn add(&mut self, raider: &RepoRaider) {
+        let repos = raider
+            .dirs
+            .iter()
+            .filter(|d| d.failed.is_none() && d.pages.iter().any(|p| p.changes))
+            .map(|dir| {
+                let repo = dir.repo.as_ref().expect("Error unwrapping repo");
+                let files = dir
+                    .pages
+                    .iter()
+                    .filter(|p| p.changes)
+                    .map(|page| {
+                        let changes: Vec<LineChange> = page
+                            .matches
+                            .iter()
+                            .filter_map(|m| {
+                                m.replace.as_ref().filter(|r| **r != m.content).map(|r| {
+                                    LineChange {
+                                        line: m.line,
+                                        content: m.content.clone(),
+                                        replace: r.clone(),
+                                    }
+                                })
+                            })
+                            .collect();
+                        // Keep files changed on disk by a command as they are now
+                        let content = page.content.clone().or_else(|| {
+                            changes
+                                .is_empty()
+                                .then(|| fs::read_to_string(&page.path).ok())
+                                .flatten()
+                        });
+                        FilePlan {
+                            path: page
+                                .relative_path
+                                .strip_prefix(&dir.relative_path)
+                                .expect("Error stripping Path prefix")
+                                .to_path_buf(),
+                            changes,
+                            content,
+                            crlf: page.crlf,
+                        }
+                    })
+                    .collect();
+                RepoPlan {
+                    path: dir.relative_path.clone(),
+                    branch: dir.branch.clone().unwrap_or_else(|| {
+                        git::get_branch_name(repo).expect("Error getting repo branch name")
+                    }),
+                    files,
+                }
+            });
+        self.repos.extend(repos);
+    }
+
+    /// Read plan from a JSON file
+    #[must_use]
+    pub fn read(path: &Path) -> Self {
+        let contents = fs::read_to_string(path).expect("Error reading plan");
+        serde_json::from_str(&contents).expect("Error parsing plan")
+    }
+
+    /// Write plan to a JSON file, removing journal of a previous plan at the same path
+    pub fn write(&self, path: &Path) {
+        let contents = serde_json::to_string_pretty(self).expect("Error serializing plan");
+        fs::write(path, contents + "\n").expect("Error writing plan");
+        let journal = Journal::path(path);
+        if journal.exists() {
+            fs::remove_file(&journal).expect("Error removing journal");
+            println!("Removed journal {} of previous plan", journal.display());
+        }
+    }
+
+    /// Execute plan repository by repository and branch by branch.
+    /// Completed steps are recorded in a journal next to the plan file,
+    /// so that running it again resumes from the first incomplete repository.
+    /// Lines already replaced and commits already made are not repeated.
+    /// Each repository's raider is set up with `configure`
+    pub fn execute(
+        &self,
+        plan_path: &Path,
+        dry_run: bool,
+        configure: &dyn Fn(&mut RepoRaider),
+        sign_opts: &SignOptions,
+        username: Option<&str>,
+    ) {
+        let mut journal = Journal::open(plan_path, dry_run);
+        let root = self.root.display().to_string();
+
+        for repo_plan in &self.repos {
+            let relative = repo_plan.path.as_path();
+            let applied = journal.is_done(repo_plan, APPLIED);
+            let committed = self.commit.is_none() || journal.is_done(repo_plan, COMMITTED);
+            let pushed = !self.push || journal.is_done(repo_plan, PUSHED);
+            if applied && committed && pushed {
+                println!(
+                    "Repo {} branch {} already done",
+                    relative.display(),
+                    repo_plan.branch
+                );
+                continue;
+            }
+
+            // Run each repository with its own raider
+            let path = self.root.join(relative);
+            let repo = git::get_repo(&path).expect("Error getting repo");
+            let mut raider = RepoRaider::new(root.clone(), dry_run);
+            configure(&mut raider);
+            raider.dirs = vec![Directory::new(path, Some(repo), relative.to_path_buf())];
+            let selector = match repo_plan.branch.strip_prefix("tags/") {
+                Some(tag) => format!("tag:^{}$", regex::escape(tag)),
+                None => format!("^{}$", regex::escape(&repo_plan.branch)),
+            };
+            raider.select_branches(Some(&selector), None);
+            if !raider.next_branch() {
+                println!("  WARNING: branch {} not found", repo_plan.branch);
+                continue;
+            }
+            raider.dirs[0].pages = repo_plan
+                .files
+                .iter()
+                .map(|f| Self::page(&raider.dirs[0], f, applied))
+                .collect();
+
+            if !applied {
+                raider.apply();
+            }
+            raider.stage();
+            if !applied {
+                journal.record(repo_plan, APPLIED);
+            }
+
+            if let (Some(msg), false) = (&self.commit, committed) {
+                let repo = raider.dirs[0].repo.as_ref().expect("Error unwrapping repo");
+                if dry_run
+                    || !git::staged_files(repo)
+                        .expect("Error reading index")
+                        .is_empty()
+                {
+                    raider.commit(msg, sign_opts, false);
+                } else {
+                    println!("  Nothing to commit, already committed");
+                }
+                if raider.dirs[0].failed.is_some() {
+                    continue;
+                }
+                journal.record(repo_plan, COMMITTED);
+            }
+
+            if !pushed
+                && raider
+                    .remote_push(username.expect("ERROR: Git username must be specified for push"))
+            {
+                journal.record(repo_plan, PUSHED);
+            }
+        }
+    }
+
+    /// Create page for a planned file, leaving out lines that are already replaced
+    fn page(dir: &Directory, file: &FilePlan, applied: bool) -> Page {
+        let path = dir.path.join(&file.path);
+        let relative = dir.relative_path.join(&file.path);
+        let mut page = Page::new(path.clone(), relative.clone());
+        page.changes = true;
+        page.crlf = file.crlf;
+        if applied {
+            return page;
+        }
+
+        let current = fs::read_to_string(&path).unwrap_or_default();
+        let lines: Vec<&str> = current.lines().collect();
+        page.content = file
+            .content
+            .clone()
+            .filter(|c| c.lines().ne(current.lines()));
+        page.matches = file
+            .changes
+            .iter()
+            .filter(|c| lines.get(c.line) != Some(&c.replace.as_str()))
+            .map(|c| Match {
+                line: c.line,
+                content: c.content.clone(),
+                replace: Some(c.replace.clone()),
+                captures: Vec::new(),
+                page: Rc::new(Page::new(path.clone(), relative.clone())),
+            })
+            .collect();
+        page
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Create repositories with a committed `values.yaml` under a fresh directory
+    fn scratch_repos(name: &str, repos: &[&str]) -> PathBuf {
+        let root =
+            std::env::temp_dir().join(format!("gitraider-plan-{name}-{}", std::process::id()));
+        fs::remove_dir_all(&root).ok();
+        for name in repos {
+            let path = root.join(name);
+            fs::create_dir_all(&path).unwrap();
+            fs::write(path.join("values.yaml"), "host: prod\n").unwrap();
+            let repo = git2::Repository::init(&path).unwrap();
+            let mut config = repo.config().unwrap();
+            config.set_str("user.name", "Test").unwrap();
+            config.set_str("user.email", "test@example.com").unwrap();
+            config.set_bool("commit.gpgsign", false).unwrap();
+            let mut index = repo.index().unwrap();
+            index.add_path(Path::new("values.yaml")).unwrap();
+            index.write().unwrap();
+            let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+            let signature = repo.signature().unwrap();
+            repo.commit(Some("HEAD"), &signature, &signature, "init", &tree, &[])
+                .unwrap();
+        }
+        root
+    }
+
+    /// Head commit ids of repositories
+    fn heads(root: &Path, repos: &[&str]) -> Vec<git2::Oid> {
+        repos
+            .iter()
+            .map(|name| {
+                let repo = git2::Repository::open(root.join(name)).unwrap();
+                let head = repo.head().unwrap().target().unwrap();
+                head
+            })
+            .collect()
+    }
+
+    fn execute(plan_path: &Path, plan: &Plan) {
+        plan.execute(plan_path, false, &|_| {}, &SignOptions::default(), None);
+    }
+
+    #[test]
+    fn plan_resume() {
+        let root = scratch_repos("resume", &["a", "b"]);
+        let plan_path =
+            std::env::temp_dir().join(format!("gitraider-plan-{}.json", std::process::id()));
+
+        let mut raider = RepoRaider::new(root.display().to_string(), false);
+        raider.find_repos();
+        raider.select_branches(None, None);
+        raider.next_branch();
+        raider.match_files("values");
+        raider.match_lines("prod");
+        raider.replace("prod", "stage", false);
+        let mut plan = Plan::new(raider.path.clone(), Some("Use stage"), false);
+        plan.add(&raider);
+        drop(raider);
+        assert_eq!(plan.repos.len(), 2);
+        plan.write(&plan_path);
+        let names: Vec<String> = plan
+            .repos
+            .iter()
+            .map(|r| r.path.display().to_string())
+            .collect();
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+        let initial = heads(&root, &names);
+
+        // Run interrupted after first repository
+        let mut partial = Plan::read(&plan_path);
+        partial.repos.truncate(1);
+        execute(&plan_path, &partial);
+        let after_first = heads(&root, &names);
+        assert_ne!(after_first[0], initial[0]);
+        assert_eq!(after_first[1], initial[1]);
+
+        // Resumed run leaves completed repository alone
+        execute(&plan_path, &Plan::read(&plan_path));
+        let done = heads(&root, &names);
+        assert_eq!(done[0], after_first[0]);
+        assert_ne!(done[1], initial[1]);
+        for name in &names {
+            let values = fs::read_to_string(root.join(name).join("values.yaml")).unwrap();
+            assert_eq!(values, "host: stage\n");
+        }
+
+        // Completed plan does nothing
+        execute(&plan_path, &Plan::read(&plan_path));
+        assert_eq!(heads(&root, &names), done);
+
+        // Writing a new plan starts over
+        plan.write(&plan_path);
+        assert!(!Journal::path(&plan_path).exists());
+        fs::remove_file(&plan_path).ok();
+        fs::remove_dir_all(root).ok();
+    }
+}
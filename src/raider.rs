@@ -1,19 +1,47 @@
 use std::fs;
 use std::io::{BufRead, BufReader, BufWriter, Read, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-use regex::Regex;
+use regex::{Captures, NoExpand, Regex};
 
+use crate::filter;
 use crate::func;
 use crate::git;
+use crate::sign;
 use crate::structs;
+use crate::structured;
+use crate::template;
+
+/// Number of temporary worktrees added, keeping their names unique within a process
+static WORKTREES: AtomicUsize = AtomicUsize::new(0);
 
 /// Repo Raider struct
 pub struct RepoRaider {
     pub path: PathBuf,
     pub dirs: Vec<structs::Directory>,
+    /// Repositories filtered out with reason
+    pub skipped: Vec<(PathBuf, String)>,
     pub dry_run: bool,
+    /// Whether to include submodules of found repositories
+    pub submodules: bool,
+    /// Options for walking directories
+    pub walk: func::WalkOptions,
+    /// Maximum size of files to search in bytes
+    pub max_file_size: Option<u64>,
+    /// Options for compiling file, line and select patterns
+    pub patterns: func::PatternOptions,
+    /// Repositories without branches left to process in current round
+    pub parked: Vec<structs::Directory>,
+    /// Whether to stay on checked out branches instead of restoring original HEAD when dropped
+    pub stay: bool,
+    /// Whether to change branches in temporary linked worktrees instead of checking them out
+    pub worktree: bool,
+    /// Remote to push to and resolve branches from
+    pub remote: String,
+    /// Commit author as `Name <email>`, user from git config if not set
+    pub author: Option<String>,
 }
 
 /// Repo Raider Implementation
@@ -24,71 +52,386 @@ impl RepoRaider {
         Self {
             path: abs_path,
             dirs: Vec::new(),
+            skipped: Vec::new(),
             dry_run,
+            submodules: false,
+            walk: func::WalkOptions::default(),
+            max_file_size: None,
+            patterns: func::PatternOptions::default(),
+            parked: Vec::new(),
+            stay: false,
+            worktree: false,
+            remote: "origin".to_string(),
+            author: None,
         }
     }
 
     /// Searches for directories with a specific name and outputs a result vector
     pub fn find_dirs(&mut self, name: &str) {
-        self.dirs = func::find_dirs(&self.path, name, &false)
+        self.dirs = func::find_dirs(&self.path, name, &false, &self.walk)
             .iter()
-            .map(|x| structs::Directory {
-                path: x.clone(),
-                repo: None,
-                pages: Vec::new(),
-                relative_path: x
-                    .strip_prefix(&self.path)
-                    .expect("Error prefixing PATH")
-                    .to_path_buf(),
+            .map(|x| {
+                structs::Directory::new(
+                    x.clone(),
+                    None,
+                    x.strip_prefix(&self.path)
+                        .expect("Error prefixing PATH")
+                        .to_path_buf(),
+                )
             })
             .collect();
     }
 
-    /// Searches for directories that are git repositories
-    /// and saves them as a vector of Directory structs
+    /// Searches for directories that are git repositories,
+    /// including nested repositories, worktrees and bare repositories,
+    /// and saves them as a vector of Directory structs.
+    /// Submodules are only included if `submodules` is set
     pub fn find_repos(&mut self) {
-        self.dirs = func::find_dirs(&self.path, ".git", &false)
+        self.dirs = func::find_repos(&self.path, &self.walk)
+            .iter()
+            .map(|x| {
+                structs::Directory::new(
+                    x.clone(),
+                    Some(git::get_repo(x).expect("Error getting repo")),
+                    x.strip_prefix(&self.path)
+                        .expect("Error prefixing Path")
+                        .to_path_buf(),
+                )
+            })
+            .collect();
+
+        // Mark submodules of found superprojects
+        let submodules: Vec<(PathBuf, PathBuf)> = self
+            .dirs
             .iter()
-            .map(|x| structs::Directory {
-                path: x.clone(),
-                repo: Some(git::get_repo(x).expect("Error getting repo")),
-                pages: Vec::new(),
-                relative_path: x
-                    .strip_prefix(&self.path)
-                    .expect("Error prefixing Path")
-                    .to_path_buf(),
+            .filter_map(|d| d.repo.as_ref().map(|r| (d, r)))
+            .filter_map(|(d, r)| Some((d, r.workdir()?, r.submodules().ok()?)))
+            .flat_map(|(d, workdir, subs)| {
+                subs.iter()
+                    .map(|s| (workdir.join(s.path()), d.path.clone()))
+                    .collect::<Vec<_>>()
             })
             .collect();
+        self.dirs.iter_mut().for_each(|dir| {
+            if let Some((_, superproject)) = submodules.iter().find(|(p, _)| *p == dir.path) {
+                dir.kind = structs::RepoKind::Submodule;
+                dir.superproject = Some(superproject.clone());
+            }
+        });
+
+        if !self.submodules {
+            let (kept, skipped): (Vec<_>, Vec<_>) = std::mem::take(&mut self.dirs)
+                .into_iter()
+                .partition(|d| d.kind != structs::RepoKind::Submodule);
+            self.dirs = kept;
+            skipped.iter().for_each(|d| {
+                self.skipped
+                    .push((d.relative_path.clone(), "submodule".to_string()));
+            });
+        }
     }
 
-    /// Checks out a branch in all directories that are repos
-    pub fn checkout_branch(&mut self, pattern: &str) {
-        let re = Regex::new(pattern).expect("Error compiling regex");
+    /// Filters found repositories, keeping skipped ones with a reason
+    pub fn filter_repos(&mut self, filter: &filter::RepoFilter) {
+        let (kept, skipped): (Vec<_>, Vec<_>) = std::mem::take(&mut self.dirs)
+            .into_iter()
+            .map(|dir| {
+                let result = filter.check(&dir, &self.remote);
+                (dir, result)
+            })
+            .partition(|(_, result)| result.is_ok());
+
+        self.dirs = kept.into_iter().map(|(dir, _)| dir).collect();
+        skipped.into_iter().for_each(|(dir, result)| {
+            let reason = result.expect_err("Error unwrapping skip reason");
+            eprintln!("Skipping {}: {reason}", dir.relative_path.display());
+            self.skipped.push((dir.relative_path.clone(), reason));
+        });
+    }
+
+    /// Selects branches to process in each repository:
+    /// all local branches matching a pattern, or the current branch without one.
+    /// `@current` and `@default` select the current and `origin/HEAD` branch,
+    /// `tag:REGEX` selects matching tags as `tags/NAME` for detached checkout.
+    /// A pick reduces several matches to one. Bare repositories are skipped
+    pub fn select_branches(&mut self, pattern: Option<&str>, pick: Option<structs::BranchPick>) {
+        let selector = pattern.unwrap_or(CURRENT_BRANCH);
+        let re = match selector {
+            CURRENT_BRANCH | DEFAULT_BRANCH => None,
+            s => Some(
+                Regex::new(s.strip_prefix(TAG_PREFIX).unwrap_or(s)).expect("Error compiling regex"),
+            ),
+        };
+        let tags = selector.starts_with(TAG_PREFIX);
+
         self.dirs.iter_mut().for_each(|dir| {
-            if let Some(repo) = &dir.repo {
-                println!("Repo {}", &dir.relative_path.display());
-                let branches = git::get_branches(repo).expect("  ERROR unwrapping repo's Branches");
-                let mut matches = 0;
-
-                // Loop through branches
-                for branch in branches {
-                    let b = branch.expect("Error unwrapping branch").0;
-                    let refname = git::get_ref(&b);
-
-                    // If branch's refname matches regex pattern then checkout
-                    if re.is_match(refname) {
-                        git::checkout_branch(repo, &b).expect("  ERROR checking out branch");
-                        matches += 1;
-
-                        // If there were more than on match
-                        // Output a warning
-                        if matches > 1 {
-                            println!("    WARNING: More than one branch matched");
-                        }
+            let Some(repo) = &dir.repo else {
+                println!("   WARNING: folder is not a repository");
+                return;
+            };
+            if dir.kind == structs::RepoKind::Bare {
+                println!("Skipping bare repository {}", dir.relative_path.display());
+                return;
+            }
+
+            dir.branches = match (&re, selector) {
+                (Some(re), _) if tags => git::get_tags(repo)
+                    .expect("  ERROR unwrapping repo's tags")
+                    .into_iter()
+                    .filter(|name| re.is_match(name))
+                    .map(|name| format!("tags/{name}"))
+                    .collect(),
+                (Some(re), _) => git::get_branches(repo)
+                    .expect("  ERROR unwrapping repo's Branches")
+                    .map(|b| git::get_ref(&b.expect("Error unwrapping branch").0).to_string())
+                    .filter(|name| re.is_match(name))
+                    .collect(),
+                (None, DEFAULT_BRANCH) => match git::default_branch(repo, &self.remote) {
+                    Ok(name) => vec![name],
+                    Err(e) => {
+                        println!(
+                            "Repo {}: no default branch, {}",
+                            dir.relative_path.display(),
+                            e.message()
+                        );
+                        Vec::new()
                     }
+                },
+                (None, _) => git::get_branch_name(repo).into_iter().collect(),
+            };
+            if let (Some(pick), true) = (pick, dir.branches.len() > 1) {
+                dir.branches = pick_branch(repo, std::mem::take(&mut dir.branches), pick)
+                    .into_iter()
+                    .collect();
+            }
+
+            if dir.branches.is_empty() {
+                println!("Repo {}: no branch matched", dir.relative_path.display());
+            } else if dir.branches.len() > 1 {
+                println!(
+                    "Repo {}: {} branches matched",
+                    dir.relative_path.display(),
+                    dir.branches.len()
+                );
+            }
+        });
+    }
+
+    /// Finishes current branch of each repository, keeping its results in history,
+    /// and checks out the next selected branch.
+    /// Repositories without branches left are parked until all are done.
+    /// Returns `false` once no repository has a branch left
+    pub fn next_branch(&mut self) -> bool {
+        self.dirs.append(&mut self.parked);
+        self.remove_worktrees();
+        self.dirs.iter_mut().for_each(structs::Directory::archive);
+
+        let (active, parked): (Vec<_>, Vec<_>) = std::mem::take(&mut self.dirs)
+            .into_iter()
+            .partition(|d| !d.branches.is_empty());
+        self.parked = parked;
+
+        for mut dir in active {
+            let branch = dir.branches.remove(0);
+            println!("Repo {}", &dir.relative_path.display());
+            if self.worktree {
+                let count = WORKTREES.fetch_add(1, Ordering::Relaxed);
+                let name = format!("gitraider-{}-{count}", std::process::id());
+                if let Err(e) = add_worktree(&mut dir, &self.remote, &branch, name) {
+                    println!("  ERROR adding worktree for {branch}: {}", e.message());
+                    dir.failed = Some(format!("worktree not added, {}", e.message()));
+                    dir.branch = Some(branch);
+                    self.parked.push(dir);
+                    continue;
                 }
-            } else {
-                println!("   WARNING: folder is not a repository");
+            } else if let Err(e) = checkout(&mut dir, &self.remote, &branch) {
+                println!("  ERROR checking out {branch}: {}", e.message());
+                dir.failed = Some(format!("checkout failed, {}", e.message()));
+                dir.branch = Some(branch);
+                self.parked.push(dir);
+                continue;
+            }
+            dir.branch = Some(branch);
+            self.dirs.push(dir);
+        }
+
+        if self.dirs.is_empty() {
+            // Repositories whose branch could not be checked out may have branches left
+            if self.parked.iter().any(|d| !d.branches.is_empty()) {
+                return self.next_branch();
+            }
+            // Restore all repositories in a stable order
+            self.dirs.append(&mut self.parked);
+            self.dirs.iter_mut().for_each(structs::Directory::archive);
+            self.dirs.sort_by(|a, b| a.path.cmp(&b.path));
+            false
+        } else {
+            true
+        }
+    }
+
+    /// Removes temporary worktrees and switches directories back to their main checkout.
+    /// Commits of a worktree's temporary branch are fast-forwarded to the changed branch
+    /// where it is not checked out. Worktrees with uncommitted changes or with commits
+    /// that are neither on the changed branch nor pushed are kept and their path printed
+    pub fn remove_worktrees(&mut self) {
+        let remote = &self.remote;
+        self.dirs
+            .iter_mut()
+            .chain(self.parked.iter_mut())
+            .for_each(|dir| {
+                let Some(worktree) = &dir.worktree else {
+                    return;
+                };
+                let branch = worktree.branch.clone();
+                let repo = dir.repo.as_ref().expect("Error unwrapping repo");
+                let dirty = git::has_tracked_changes(repo).unwrap_or(true);
+                let head = repo.head().ok().and_then(|h| h.target());
+                let path = dir.path.clone();
+                let name = dir.leave_worktree().expect("Error leaving worktree");
+                let repo = dir.repo.as_ref().expect("Error unwrapping repo");
+
+                let unsaved = if dirty {
+                    Some("uncommitted changes".to_string())
+                } else {
+                    head.filter(|&oid| {
+                        tag_ref(repo, &branch).is_none()
+                            && !git::fast_forward(repo, &branch, oid).unwrap_or(false)
+                            && !git::contains(repo, &format!("refs/remotes/{remote}/{branch}"), oid)
+                    })
+                    .map(|oid| format!("commit {oid} is not on {branch} nor pushed"))
+                };
+                if let Some(reason) = unsaved {
+                    println!(
+                        "Repo {}: kept worktree {} on branch {name}, {reason}",
+                        dir.relative_path.display(),
+                        path.display()
+                    );
+                    return;
+                }
+                match git::remove_worktree(repo, &name) {
+                    Ok(()) => println!(
+                        "Repo {}: removed worktree {name}",
+                        dir.relative_path.display()
+                    ),
+                    Err(e) => println!(
+                        "Repo {}: WARNING: failed to remove worktree {name}, {}",
+                        dir.relative_path.display(),
+                        e.message()
+                    ),
+                }
+            });
+    }
+
+    /// Checks out the original HEAD of each repository that switched branches.
+    /// Repositories with uncommitted changes made during the run stay where they are
+    /// so that changes are not carried over to another branch, changes made before are
+    /// carried back
+    pub fn restore_heads(&mut self) {
+        self.dirs
+            .iter_mut()
+            .chain(self.parked.iter_mut())
+            .for_each(|dir| {
+                let (Some(repo), Some(head)) = (&dir.repo, dir.original_head.take()) else {
+                    return;
+                };
+                let name = head.strip_prefix("refs/heads/").unwrap_or(&head);
+                let changes = git::tracked_changes(repo);
+                if changes.map_or(true, |c| !c.is_empty() && c != dir.original_changes) {
+                    println!(
+                        "Repo {}: uncommitted changes, staying instead of restoring {name}",
+                        dir.relative_path.display()
+                    );
+                    return;
+                }
+                match git::restore_head(repo, &head) {
+                    Ok(()) => println!("Repo {}: restored {name}", dir.relative_path.display()),
+                    Err(e) => println!(
+                        "Repo {}: WARNING: failed to restore {name}, {}",
+                        dir.relative_path.display(),
+                        e.message()
+                    ),
+                }
+            });
+    }
+
+    /// Maximum number of branches selected in a repository
+    #[must_use]
+    pub fn max_branches(&self) -> usize {
+        self.dirs
+            .iter()
+            .map(|d| d.branches.len())
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// Collects files staged by an earlier run in each repository as changed pages,
+    /// so that they can be validated and committed. Other staged files are left alone
+    pub fn find_staged(&mut self) {
+        self.dirs.iter_mut().for_each(|dir| {
+            let Some(repo) = &dir.repo else {
+                return;
+            };
+            let Some(workdir) = repo.workdir().map(Path::to_path_buf) else {
+                return;
+            };
+            // Leave staged files that were not changed by gitraider
+            let recorded = git::recorded_staged(repo);
+            let (staged, other): (Vec<_>, Vec<_>) = git::staged_files(repo)
+                .expect("Error reading index")
+                .into_iter()
+                .partition(|f| recorded.contains(f));
+            if !other.is_empty() {
+                println!(
+                    "Repo {}: leaving {} staged files not changed by gitraider",
+                    dir.relative_path.display(),
+                    other.len()
+                );
+            }
+            dir.pages = staged
+                .iter()
+                .map(|f| {
+                    let mut page = structs::Page::new(workdir.join(f), dir.relative_path.join(f));
+                    page.changes = true;
+                    page
+                })
+                .collect();
+            if !dir.pages.is_empty() {
+                println!(
+                    "Repo {} has {} staged files",
+                    dir.relative_path.display(),
+                    dir.pages.len()
+                );
+            }
+        });
+    }
+
+    /// Keeps only repositories whose commits not pushed to upstream were all made by gitraider.
+    /// Branches without upstream are kept if HEAD is a gitraider commit
+    pub fn retain_unpushed(&mut self) {
+        self.dirs.iter_mut().for_each(|dir| {
+            dir.amended = dir.repo.as_ref().and_then(git::amended_upstream);
+        });
+        self.dirs.retain(|dir| {
+            let Some(repo) = &dir.repo else {
+                return false;
+            };
+            match git::ahead_behind(repo) {
+                Ok(Some((ahead, _))) if ahead > 0 => {
+                    let raided = git::unpushed_are_raided(repo).unwrap_or(false);
+                    if !raided {
+                        println!(
+                            "Skipping push of {}, unpushed commits not made by gitraider",
+                            dir.relative_path.display()
+                        );
+                    }
+                    raided
+                }
+                Ok(Some(_)) => false,
+                Ok(None) => repo.head().is_ok_and(|h| {
+                    h.is_branch() && h.peel_to_commit().is_ok_and(|c| git::has_trailer(&c))
+                }),
+                Err(_) => false,
             }
         });
     }
@@ -96,67 +439,203 @@ impl RepoRaider {
     /// Recursively matches for filenames with a specific name
     /// and saves them as a vector of Page structs
     pub fn match_files(&mut self, pattern: &str) {
-        self.dirs.iter_mut().for_each(|dir| {
-            let f: Vec<structs::Page> =
-                func::find_files(dir.path.to_str().expect("Error unwrapping Path"), pattern)
-                    .iter()
-                    .map(|x| structs::Page {
-                        path: x.clone(),
-                        matches: Vec::new(),
-                        changes: false,
-                        relative_path: x
-                            .strip_prefix(&self.path)
-                            .expect("Error prefixing Path")
-                            .to_path_buf(),
-                    })
-                    .collect();
-            dir.pages.extend(f);
-        });
+        let re = func::build_regex(pattern, &self.patterns);
+        self.match_files_by(&filter::FileSelector::new(Some(re), &[], &[], &[]));
+    }
+
+    /// Recursively matches for files selected by filename regex,
+    /// repo relative path regexes and include and exclude globs
+    /// and saves them as a vector of Page structs.
+    /// Files marked as binary or generated in .gitattributes
+    /// and files using filter drivers such as LFS are skipped
+    pub fn match_files_by(&mut self, selector: &filter::FileSelector) {
+        self.dirs
+            .iter_mut()
+            .filter(|d| d.kind != structs::RepoKind::Bare)
+            .for_each(|dir| {
+                for x in func::find_files_by(&dir.path, selector, &self.walk) {
+                    // Directory may be switched to a worktree outside of raider's path
+                    let relative = x.strip_prefix(&dir.path).expect("Error prefixing Path");
+                    let mut page = structs::Page::new(x.clone(), dir.relative_path.join(relative));
+
+                    if let Some(repo) = &dir.repo {
+                        if let Some(reason) = Self::attr_skip_reason(repo, relative) {
+                            if reason == structs::SkipReason::Lfs {
+                                println!(
+                                    "Refusing to edit LFS file {}",
+                                    page.relative_path.display()
+                                );
+                            }
+                            dir.skipped_files.push((page.relative_path, reason));
+                            continue;
+                        }
+                        page.crlf = git::get_attr(repo, relative, "eol")
+                            .ok()
+                            .flatten()
+                            .is_some_and(|eol| eol == "crlf");
+                    }
+                    dir.pages.push(page);
+                }
+            });
+    }
+
+    /// Get reason to skip a file based on its git attributes, if any
+    fn attr_skip_reason(repo: &git2::Repository, relative: &Path) -> Option<structs::SkipReason> {
+        let attr = |name| git::get_attr(repo, relative, name).ok().flatten();
+
+        match attr("filter").as_deref() {
+            Some("lfs") => return Some(structs::SkipReason::Lfs),
+            Some("false") | None => {}
+            Some(_) => return Some(structs::SkipReason::Filtered),
+        }
+        if attr("binary").as_deref() == Some("true") || attr("diff").as_deref() == Some("false") {
+            return Some(structs::SkipReason::Binary);
+        }
+        if attr("linguist-generated").as_deref() == Some("true") {
+            return Some(structs::SkipReason::Generated);
+        }
+        None
     }
 
     /// Recursively searches for all lines matching a pattern in a file
-    /// and saves them as a vector of Match structs
+    /// and saves them as a vector of Match structs.
     pub fn match_lines(&mut self, pattern: &str) {
-        let re = Regex::new(pattern).expect("Error compiling regex");
+        let filter = filter::LineFilter::new(func::build_regex(pattern, &self.patterns));
+        self.match_lines_by(&filter);
+    }
+
+    /// Recursively searches for all lines selected by a line filter
+    /// and saves them as a vector of Match structs.
+    /// Binary and oversized files are skipped and summarised per repository
+    pub fn match_lines_by(&mut self, filter: &filter::LineFilter) {
+        let re = &filter.line;
+        let max_file_size = self.max_file_size;
         self.dirs.iter_mut().for_each(|dir| {
+            // Skip files before reading them
+            let (pages, skipped): (Vec<_>, Vec<_>) = std::mem::take(&mut dir.pages)
+                .into_iter()
+                .map(|p| {
+                    let reason = Self::skip_reason(dir.repo.as_ref(), &dir.path, &p, max_file_size);
+                    (p, reason)
+                })
+                .partition(|(_, reason)| reason.is_none());
+            dir.pages = pages.into_iter().map(|(p, _)| p).collect();
+            dir.skipped_files.extend(
+                skipped
+                    .into_iter()
+                    .filter_map(|(p, reason)| reason.map(|r| (p.relative_path, r))),
+            );
+
             dir.pages.iter_mut().for_each(|page| {
                 // Open File
                 let file = fs::File::open(&page.path).expect("Error reading file");
 
-                // Create a buffered reader and loop through file's lines
-                let reader = BufReader::new(file);
-                for (line, content) in reader.lines().enumerate() {
-                    match content {
-                        // An error usually results from non utf-8 encoded file
-                        // i.e. binary files
-                        Err(e) => println!("{}. Skipping file {}", e, page.path.display()),
-
-                        // If content is a string and matches Regex,
-                        // then save as a new Match struct
-                        Ok(content) => {
-                            if re.is_match(content.as_str()) {
-                                let new_match = structs::Match {
-                                    line: line as i16,
-                                    content,
-                                    replace: None,
-                                    page: Rc::new(page.clone()),
-                                };
-                                page.matches.push(new_match);
-                            }
-                        }
-                    }
+                // Read all lines first so that filter can look at their context.
+                // An error usually results from non utf-8 encoded file
+                // i.e. binary files
+                let Ok(lines) = BufReader::new(file).lines().collect::<Result<Vec<_>, _>>() else {
+                    dir.skipped_files
+                        .push((page.relative_path.clone(), structs::SkipReason::Binary));
+                    return;
+                };
+
+                // If line is selected by filter, then save as a new Match struct
+                for line in (0..lines.len()).filter(|&n| filter.is_match(&lines, n)) {
+                    let content = lines[line].clone();
+                    let Some(caps) = re.captures(content.as_str()) else {
+                        continue;
+                    };
+                    // Keep named groups for replacement templates
+                    let captures = re
+                        .capture_names()
+                        .flatten()
+                        .filter_map(|n| {
+                            caps.name(n)
+                                .map(|m| (n.to_string(), m.as_str().to_string()))
+                        })
+                        .collect();
+                    let new_match = structs::Match {
+                        line,
+                        content,
+                        replace: None,
+                        captures,
+                        page: Rc::new(page.clone()),
+                    };
+                    page.matches.push(new_match);
                 }
             });
+
+            let skipped_files = &dir.skipped_files;
+            dir.pages
+                .retain(|p| !skipped_files.iter().any(|(s, _)| *s == p.relative_path));
+
+            if let Some(summary) = dir.skipped_summary() {
+                println!(
+                    "Repo {} skipped {summary} files",
+                    dir.relative_path.display()
+                );
+            }
+        });
+    }
+
+    /// Get reason to skip a file without reading all of it, if any
+    fn skip_reason(
+        repo: Option<&git2::Repository>,
+        dir_path: &Path,
+        page: &structs::Page,
+        max_file_size: Option<u64>,
+    ) -> Option<structs::SkipReason> {
+        let size = fs::metadata(&page.path).map(|m| m.len()).unwrap_or(0);
+        if max_file_size.is_some_and(|max| size > max) {
+            return Some(structs::SkipReason::Oversized);
+        }
+
+        // Files marked `-text` or `binary` in .gitattributes
+        let text_attr = repo.and_then(|r| {
+            let relative = page.path.strip_prefix(dir_path).ok()?;
+            git::get_attr(r, relative, "text").ok()?
         });
+        if text_attr.as_deref() == Some("false") || func::is_binary(&page.path).unwrap_or(true) {
+            return Some(structs::SkipReason::Binary);
+        }
+        None
     }
 
-    /// Creates a replace string for Match struct
-    pub fn replace(&mut self, select: &str, replace: &str) {
-        let re = Regex::new(select).expect("Error compiling regex");
+    /// Creates a replace string for Match struct.
+    /// Replacement is a template expanded with groups captured by select
+    /// and line patterns and repo, branch and file placeholders,
+    /// unless `literal` is set
+    pub fn replace(&mut self, select: &str, replace: &str, literal: bool) {
+        let re = func::build_regex(select, &self.patterns);
         self.dirs.iter_mut().for_each(|dir| {
+            // Selected branch or tag name, worktrees are on a temporary branch
+            let branch = match (&dir.repo, &dir.branch) {
+                (Some(repo), Some(b)) if tag_ref(repo, b).is_some() => {
+                    b.strip_prefix("tags/").unwrap_or(b).to_string()
+                }
+                (_, Some(b)) => b.clone(),
+                (Some(repo), None) => git::get_branch_name(repo).unwrap_or_default(),
+                (None, None) => String::new(),
+            };
             dir.pages.iter_mut().for_each(|page| {
+                let context = template::Context {
+                    repo: dir.relative_path.to_string_lossy().to_string(),
+                    branch: branch.clone(),
+                    file: page
+                        .relative_path
+                        .strip_prefix(&dir.relative_path)
+                        .unwrap_or(&page.relative_path)
+                        .to_string_lossy()
+                        .to_string(),
+                };
                 page.matches.iter_mut().for_each(|mat| {
-                    let res = re.replace(mat.content.as_str(), replace);
+                    let res = if literal {
+                        re.replace(mat.content.as_str(), NoExpand(replace))
+                    } else {
+                        re.replace(mat.content.as_str(), |caps: &Captures| {
+                            template::expand(replace, caps, &mat.captures, &context)
+                        })
+                    };
                     let replace_string = res.to_string();
 
                     println!("Repo {}", &dir.relative_path.display());
@@ -180,6 +659,93 @@ impl RepoRaider {
         });
     }
 
+    /// Edit value at a key path in YAML, JSON and TOML files
+    /// and saves edited lines as Match structs
+    pub fn edit_keys(&mut self, key_path: &str, value: &str) {
+        let segments = structured::parse_key_path(key_path);
+        self.dirs.iter_mut().for_each(|dir| {
+            dir.pages.iter_mut().for_each(|page| {
+                let Some(format) = structured::Format::from_path(&page.path) else {
+                    return;
+                };
+                let contents = fs::read_to_string(&page.path).expect("Error reading file");
+
+                match structured::edit(format, &contents, &segments, value) {
+                    Err(e) => println!("{e}. Skipping file {}", page.path.display()),
+                    Ok(None) => {}
+                    Ok(Some((line, replace))) => {
+                        let content = contents
+                            .lines()
+                            .nth(line)
+                            .expect("Error getting edited line")
+                            .to_string();
+                        if content != replace {
+                            page.changes = true;
+                            let new_match = structs::Match {
+                                line,
+                                content,
+                                replace: Some(replace),
+                                captures: Vec::new(),
+                                page: Rc::new(page.clone()),
+                            };
+                            page.matches.push(new_match);
+                        }
+                    }
+                }
+            });
+        });
+    }
+
+    /// Transform files with a shell command instead of replace patterns.
+    /// Command runs per matched file with `{}` substituted by file path,
+    /// or once in repository root if `per_root` is set.
+    /// With `only_matched` files without line matches are skipped
+    pub fn exec(&mut self, cmd: &str, per_root: bool, only_matched: bool) {
+        self.dirs.iter_mut().for_each(|dir| {
+            let Some(workdir) = dir.repo.as_ref().and_then(|r| r.workdir()) else {
+                return;
+            };
+            let workdir = workdir.to_path_buf();
+            let repo = dir.repo.as_ref().expect("Error unwrapping repo");
+            println!("Repo {}", &dir.relative_path.display());
+
+            if per_root {
+                if self.dry_run {
+                    println!("  Would have run '{cmd}'");
+                    return;
+                }
+                let (_, changed) = exec_changes(repo, cmd, &workdir, None);
+                add_exec_pages(dir, &workdir, changed);
+                return;
+            }
+
+            let files: Vec<PathBuf> = dir
+                .pages
+                .iter()
+                .filter(|p| !only_matched || !p.matches.is_empty())
+                .map(|p| {
+                    p.path
+                        .strip_prefix(&workdir)
+                        .unwrap_or(&p.path)
+                        .to_path_buf()
+                })
+                .collect();
+            let mut changed = Vec::new();
+            files.iter().for_each(|file_path| {
+                if self.dry_run {
+                    println!("  Would have run '{cmd}' on {}", file_path.display());
+                    return;
+                }
+                let file_cmd = func::substitute_path(cmd, file_path);
+                let (ok, changes) = exec_changes(repo, &file_cmd, &workdir, Some(file_path));
+                if ok {
+                    changed.extend(changes);
+                }
+            });
+            add_exec_pages(dir, &workdir, changed);
+        });
+    }
+
     /// Apply replace pattern to all Match structs
     /// for every Page struct in every Directory struct
     pub fn apply(&mut self) {
@@ -188,22 +754,48 @@ impl RepoRaider {
                 .iter_mut()
                 // Filter only pages that have changes
                 .filter(|p| p.changes)
+                // Skip pages already changed on disk by a command
+                .filter(|p| p.content.is_some() || p.matches.iter().any(|m| m.replace.is_some()))
                 .for_each(|page| {
-                    // Open file with buffered reader
-                    let mut file =
-                        BufReader::new(fs::File::open(&page.path).expect("Error opening file"));
-
-                    let mut file_contents = String::new();
-                    file.read_to_string(&mut file_contents)
-                        .expect("Unable to read the file");
-
-                    // Replace one line for each match if replace string exists
-                    for mat in &mut page.matches {
-                        if let Some(replace) = &mat.replace {
-                            // Replace only one match
-                            file_contents = file_contents.replacen(&mat.content, replace, 1);
+                    let file_contents = if let Some(content) = &page.content {
+                        // Use contents produced by command
+                        content.clone()
+                    } else {
+                        // Open file with buffered reader
+                        let mut file =
+                            BufReader::new(fs::File::open(&page.path).expect("Error opening file"));
+
+                        let mut file_contents = String::new();
+                        file.read_to_string(&mut file_contents)
+                            .expect("Unable to read the file");
+
+                        // Replace matched line for each match if replace string exists
+                        let replacements: Vec<(usize, &str, &str)> = page
+                            .matches
+                            .iter()
+                            .filter_map(|m| {
+                                m.replace
+                                    .as_deref()
+                                    .map(|r| (m.line, m.content.as_str(), r))
+                            })
+                            .collect();
+                        let (file_contents, missed) =
+                            func::replace_lines(&file_contents, &replacements);
+                        if missed > 0 {
+                            println!(
+                                "WARNING: {missed} lines changed since matching in {}",
+                                page.path.display()
+                            );
                         }
-                    }
+                        file_contents
+                    };
+
+                    // Keep CRLF line endings for files marked `eol=crlf`
+                    let file_contents = if page.crlf {
+                        func::to_crlf(&file_contents)
+                    } else {
+                        file_contents
+                    };
 
                     // Check if in dry run mode
                     if self.dry_run {
@@ -212,6 +804,7 @@ impl RepoRaider {
                         // Open file with buffered writer
                         let mut file = BufWriter::new(
                             fs::OpenOptions::new()
+                                .create(true)
                                 .write(true)
                                 .truncate(true)
                                 .open(&page.path)
@@ -233,6 +826,7 @@ impl RepoRaider {
                 // git::stage_all(repo).expect("Error staging all files");
 
                 // Filter only pages that have changes
+                let mut staged = Vec::new();
                 dir.pages
                     .clone()
                     .into_iter()
@@ -251,6 +845,7 @@ impl RepoRaider {
                             match git::stage_file(repo, file_repo_path) {
                                 Ok(_) => {
                                     println!("Staged '{}'", file_repo_path.display());
+                                    staged.push(file_repo_path.to_path_buf());
                                 }
                                 Err(_) => {
                                     println!("Error staging '{}'", file_repo_path.display());
@@ -258,6 +853,12 @@ impl RepoRaider {
                             }
                         }
                     });
+
+                // Remember staged files for a later commit
+                let staged: Vec<&Path> = staged.iter().map(PathBuf::as_path).collect();
+                if !staged.is_empty() {
+                    git::record_staged(repo, &staged).expect("Error recording staged files");
+                }
             } else {
                 println!(
                     "Skipping, {} is not a git repository",
@@ -267,14 +868,105 @@ impl RepoRaider {
         });
     }
 
-    /// Commit all matches  
-    pub fn commit(&mut self, msg: &str) {
+    /// Run repository hooks and a validation command on staged changes.
+    /// Changes of repositories that fail are rolled back and marked as failed
+    pub fn validate(&mut self, hooks: bool, check: Option<&str>, msg: Option<&str>) {
+        self.dirs.iter_mut().for_each(|dir| {
+            if let Some(repo) = &dir.repo {
+                // Validate only repositories with changes
+                if !dir.pages.iter().any(|p| p.changes) {
+                    return;
+                }
+
+                if self.dry_run {
+                    println!("Would have validated {}", dir.relative_path.display());
+                    return;
+                }
+
+                println!("Validating {}", dir.relative_path.display());
+                let workdir = repo.workdir().unwrap_or(&dir.path);
+                let result = if hooks {
+                    match msg {
+                        Some(msg) => git::run_commit_hooks(repo, msg),
+                        None => git::run_hook(repo, "pre-commit", &[]),
+                    }
+                    .map_err(|e| e.message().to_string())
+                } else {
+                    Ok(())
+                }
+                .and_then(|_| check.map_or(Ok(()), |cmd| func::run_command(cmd, workdir)));
+
+                if let Err(reason) = result {
+                    println!("  Validation failed, rolling back changes\n{reason}");
+
+                    // Get file paths relative to repository root
+                    let files: Vec<&Path> = dir
+                        .pages
+                        .iter()
+                        .filter(|p| p.changes)
+                        .map(|p| {
+                            p.relative_path
+                                .strip_prefix(&dir.relative_path)
+                                .expect("Error stripping Path prefix")
+                        })
+                        .collect();
+                    git::rollback(repo, &files).expect("Error rolling back changes");
+                    dir.failed = Some(reason);
+                }
+            }
+        });
+    }
+
+    /// Commit all matches, signing commits according to repo's git config
+    /// unless overridden by signing options.
+    /// With `amend` changes are folded into a previous gitraider commit
+    pub fn commit(&mut self, msg: &str, sign_opts: &sign::SignOptions, amend: bool) {
+        let author = self
+            .author
+            .as_deref()
+            .map(git::parse_signature)
+            .transpose()
+            .expect("ERROR: Invalid commit author");
         self.dirs.iter_mut().for_each(|dir| {
             if let Some(repo) = &mut dir.repo {
                 // Check if there are is at least one Match to commit
-                let do_commit = dir.pages.iter().any(|p| p.changes);
-                if do_commit && !self.dry_run {
-                    git::commit(repo, msg).expect("Error committing changes");
+                // and changes were not rolled back
+                let do_commit = dir.failed.is_none() && dir.pages.iter().any(|p| p.changes);
+                if do_commit && repo.head_detached().unwrap_or(false) {
+                    // Commit would not be reachable from any branch
+                    println!(
+                        "Skipping commit of {}, HEAD is detached",
+                        dir.relative_path.display()
+                    );
+                } else if do_commit && !self.dry_run {
+                    let signer = sign::Signer::from_config(repo, sign_opts)
+                        .expect("Error reading signing config");
+                    // Commit only changed files, leaving other staged changes alone
+                    let paths: Vec<&Path> = dir
+                        .pages
+                        .iter()
+                        .filter(|p| p.changes)
+                        .map(|p| {
+                            p.relative_path
+                                .strip_prefix(&dir.relative_path)
+                                .expect("Error stripping Path prefix")
+                        })
+                        .collect();
+                    match git::commit(repo, msg, &paths, author.as_ref(), signer.as_ref(), amend) {
+                        Ok(amended) => {
+                            dir.amended = amended;
+                            git::clear_staged_record(repo);
+                            dir.committed = true;
+                        }
+                        Err(e) => {
+                            println!(
+                                "  ERROR committing {}: {}",
+                                dir.relative_path.display(),
+                                e.message()
+                            );
+                            dir.failed = Some(format!("commit failed, {}", e.message()));
+                        }
+                    }
                 } else if do_commit {
                     println!("    Would have committed {}", dir.relative_path.display());
                 };
@@ -287,21 +979,183 @@ impl RepoRaider {
         });
     }
 
-    /// Push changes to remote
-    pub fn remote_push(&self, username: &str) {
+    /// Update gitlinks of submodules committed during the run
+    /// with a separate commit in their superprojects
+    pub fn update_gitlinks(&mut self, msg: &str, sign_opts: &sign::SignOptions) {
+        // Process deepest submodules first so that updated superprojects
+        // which are submodules themselves are picked up by their own superprojects
+        let mut order: Vec<usize> = (0..self.dirs.len()).collect();
+        order.sort_by_key(|&i| std::cmp::Reverse(self.dirs[i].path.components().count()));
+
+        for i in order {
+            let committed: Vec<PathBuf> = self
+                .dirs
+                .iter()
+                .filter(|d| d.committed && d.superproject.as_ref() == Some(&self.dirs[i].path))
+                .map(|d| d.path.clone())
+                .collect();
+            if committed.is_empty() {
+                continue;
+            }
+
+            let dir = &mut self.dirs[i];
+            let repo = dir.repo.as_mut().expect("Error unwrapping repo");
+            let workdir = repo.workdir().expect("Error getting workdir").to_path_buf();
+            let paths: Vec<String> = committed
+                .iter()
+                .map(|p| {
+                    p.strip_prefix(&workdir)
+                        .expect("Error stripping Path prefix")
+                        .to_string_lossy()
+                        .to_string()
+                })
+                .collect();
+
+            if self.dry_run {
+                println!(
+                    "Would have updated submodules {} in {}",
+                    paths.join(", "),
+                    dir.relative_path.display()
+                );
+                continue;
+            }
+
+            println!("Repo {}", dir.relative_path.display());
+            for path in &paths {
+                git::stage_submodule(repo, path).expect("Error staging submodule");
+                println!("  Staged submodule '{path}'");
+            }
+            let signer =
+                sign::Signer::from_config(repo, sign_opts).expect("Error reading signing config");
+            let gitlink_msg = format!("Update submodules {}\n\n{msg}", paths.join(", "));
+            let gitlinks: Vec<&Path> = paths.iter().map(Path::new).collect();
+            git::commit(repo, &gitlink_msg, &gitlinks, None, signer.as_ref(), false)
+                .expect("Error committing submodule update");
+            dir.committed = true;
+        }
+    }
+
+    /// Push changes to remote.
+    /// Branches with an amended commit are force pushed if remote still has the replaced commit.
+    /// Returns false if any repository failed to push
+    pub fn remote_push(&self, username: &str) -> bool {
+        let mut pushed = true;
         self.dirs.iter().for_each(|dir| {
             let repo = dir.repo.as_ref().expect("Error unwrapping repo");
 
-            if self.dry_run {
+            if dir.failed.is_some() {
+                println!(
+                    "Skipping push of {}, validation failed",
+                    dir.relative_path.display()
+                );
+            } else if repo.head_detached().unwrap_or(false) {
+                println!(
+                    "Skipping push of {}, HEAD is detached",
+                    dir.relative_path.display()
+                );
+            } else if self.dry_run {
                 println!(
                     "Would have pushed {} to remote",
                     dir.relative_path.display()
                 );
             } else {
                 println!("Pushing {} to remote", dir.relative_path.display());
-                git::push(repo, username).expect("Error pushing repository");
+                // Worktrees change a temporary branch that is pushed to the selected one
+                let dest = dir.worktree.as_ref().map(|w| w.branch.as_str());
+                if let Err(e) = git::push(repo, &self.remote, username, dest, dir.amended) {
+                    println!(
+                        "  ERROR pushing {}: {}",
+                        dir.relative_path.display(),
+                        e.message()
+                    );
+                    pushed = false;
+                }
             }
         });
+        pushed
+    }
+
+    /// Gets state of all repositories: branch, dirty working tree,
+    /// commits ahead and behind upstream and last commit
+    #[must_use]
+    pub fn status(&self) -> Vec<structs::RepoStatus> {
+        self.dirs
+            .iter()
+            .filter_map(|dir| dir.repo.as_ref().map(|r| (dir, r)))
+            .map(|(dir, repo)| {
+                let head = repo.head().ok();
+                let detached = repo.head_detached().unwrap_or(false);
+                let commit = head.as_ref().and_then(|h| h.peel_to_commit().ok());
+                let branch = match (&commit, detached) {
+                    (Some(c), true) => c
+                        .as_object()
+                        .short_id()
+                        .ok()
+                        .and_then(|id| id.as_str().map(str::to_string)),
+                    _ => git::get_branch_name(repo).ok(),
+                };
+                let ahead_behind = git::ahead_behind(repo).ok().flatten();
+                structs::RepoStatus {
+                    path: dir.relative_path.clone(),
+                    branch: branch.unwrap_or_else(|| "-".to_string()),
+                    detached,
+                    dirty: !repo.is_bare() && git::is_dirty(repo).unwrap_or(false),
+                    ahead: ahead_behind.map(|(a, _)| a),
+                    behind: ahead_behind.map(|(_, b)| b),
+                    last_commit_date: commit
+                        .as_ref()
+                        .map(|c| func::format_time(c.time().seconds(), c.time().offset_minutes())),
+                    last_commit_author: commit
+                        .as_ref()
+                        .and_then(|c| c.author().name().map(str::to_string)),
+                }
+            })
+            .collect()
+    }
+
+    /// Add proposed changes of current branches to run totals,
+    /// recording exceeded per file and per repository limits
+    pub fn count_changes(&self, totals: &mut structs::RunTotals, limits: &structs::Limits) {
+        for dir in &self.dirs {
+            let mut files = 0;
+            for page in &dir.pages {
+                let changes = page.change_count();
+                if changes == 0 {
+                    continue;
+                }
+                files += 1;
+                totals.lines += changes;
+                if let Some(max) = limits.max_changes_per_file {
+                    if changes > max {
+                        totals.exceeded.push(format!(
+                            "{} changes {changes} lines, limit is {max}",
+                            page.relative_path.display()
+                        ));
+                    }
+                }
+            }
+            if files == 0 {
+                continue;
+            }
+            totals.files += files;
+            totals.repos.insert(dir.relative_path.clone());
+            if let Some(max) = limits.max_files_per_repo {
+                if files > max {
+                    totals.exceeded.push(format!(
+                        "Repo {} changes {files} files, limit is {max}",
+                        dir.relative_path.display()
+                    ));
+                }
+            }
+        }
+    }
+
+    /// Check proposed changes against fail-safe limits,
+    /// returning all exceeded limits
+    pub fn check_limits(&self, limits: &structs::Limits) -> Result<(), Vec<String>> {
+        let mut totals = structs::RunTotals::default();
+        self.count_changes(&mut totals, limits);
+        totals.check(limits)
     }
 
     /// Gets all folders
@@ -333,10 +1187,753 @@ impl RepoRaider {
     }
 }
 
+impl Drop for RepoRaider {
+    /// Remove temporary worktrees and restore original HEAD of repositories,
+    /// also when a run fails midway
+    fn drop(&mut self) {
+        self.remove_worktrees();
+        if !self.stay {
+            self.restore_heads();
+        }
+    }
+}
+
+/// Selector for current branch
+const CURRENT_BRANCH: &str = "@current";
+/// Selector for the branch `origin/HEAD` points to
+const DEFAULT_BRANCH: &str = "@default";
+/// Prefix of tag selectors
+pub const TAG_PREFIX: &str = "tag:";
+
+/// Check out a branch or tag in the main checkout, remembering where it was before
+fn checkout(dir: &mut structs::Directory, remote: &str, branch: &str) -> Result<(), git2::Error> {
+    let repo = dir.repo.as_ref().expect("Error unwrapping repo");
+    if git::get_branch_name(repo).is_ok_and(|current| current == branch) {
+        println!("  On branch {branch}");
+        return Ok(());
+    }
+    // Original HEAD is remembered only once something was checked out
+    let head = git::head_ref(repo).ok();
+    let changes = git::tracked_changes(repo)?;
+    if let Some(tag) = tag_ref(repo, branch) {
+        git::checkout_detached(repo, &tag)?;
+    } else {
+        let b = git::local_branch(repo, remote, branch)?;
+        git::checkout_branch(repo, &b)?;
+    }
+    if dir.original_head.is_none() {
+        dir.original_head = head;
+        dir.original_changes = changes;
+    }
+    Ok(())
+}
+
+/// Add a temporary linked worktree for a branch or tag and switch directory to it
+fn add_worktree(
+    dir: &mut structs::Directory,
+    remote: &str,
+    branch: &str,
+    name: String,
+) -> Result<(), git2::Error> {
+    let path = std::env::temp_dir().join(&name);
+    let (worktree, tag) = {
+        let repo = dir.repo.as_ref().expect("Error unwrapping repo");
+        let tag = tag_ref(repo, branch);
+        // Temporary branch at the branch tip, as the branch may be checked out elsewhere
+        let (target, upstream) = match &tag {
+            Some(tag) => (repo.revparse_single(tag)?.peel_to_commit()?, None),
+            None => {
+                let local = git::local_branch(repo, remote, branch)?;
+                let upstream = local
+                    .upstream()
+                    .ok()
+                    .and_then(|u| u.name().ok().flatten().map(String::from));
+                (local.get().peel_to_commit()?, upstream)
+            }
+        };
+        let mut temp = repo.branch(&name, &target, false)?;
+        println!("  Adding worktree {} for {branch}", path.display());
+        let added = match upstream {
+            Some(upstream) => temp.set_upstream(Some(&upstream)),
+            None => Ok(()),
+        }
+        .and_then(|()| git::add_worktree(repo, &name, &path, Some(&temp)));
+        match added {
+            Ok(worktree) => (worktree, tag),
+            Err(e) => {
+                temp.delete().ok();
+                return Err(e);
+            }
+        }
+    };
+    dir.enter_worktree(name, branch.to_string(), worktree);
+    if let Some(tag) = tag {
+        let repo = dir.repo.as_ref().expect("Error unwrapping repo");
+        git::checkout_detached(repo, &tag)?;
+    }
+    Ok(())
+}
+
+/// Run a command in repository root and detect files it changed using repository status,
+/// along with the given file. Changed files are restored and their new contents returned,
+/// so that changes are counted, checked against limits and confirmed before apply writes them.
+/// Returns whether the command succeeded
+fn exec_changes(
+    repo: &git2::Repository,
+    cmd: &str,
+    workdir: &Path,
+    file: Option<&Path>,
+) -> (bool, Vec<(PathBuf, Vec<u8>)>) {
+    let before = git::changed_files(repo).expect("Error getting repo status");
+    let original = file.map(|f| fs::read(workdir.join(f)).expect("Error reading file"));
+    let result = func::run_command(cmd, workdir);
+    if let Err(e) = &result {
+        println!("  {e}");
+    }
+    let after = git::changed_files(repo).expect("Error getting repo status");
+
+    let mut changed: Vec<PathBuf> = after.into_iter().filter(|f| !before.contains(f)).collect();
+    // File may have had changes before the command ran
+    if let (Some(file), Some(original)) = (file, &original) {
+        let transformed = fs::read(workdir.join(file)).expect("Error reading file");
+        if &transformed != original && !changed.iter().any(|f| f == file) {
+            changed.push(file.to_path_buf());
+        }
+    }
+    let contents: Vec<(PathBuf, Vec<u8>)> = changed
+        .into_iter()
+        .map(|f| {
+            let content = fs::read(workdir.join(&f)).unwrap_or_default();
+            (f, content)
+        })
+        .collect();
+
+    // Restore files, writing back the given file's original contents
+    let paths: Vec<&Path> = contents
+        .iter()
+        .map(|(f, _)| f.as_path())
+        .filter(|f| Some(*f) != file)
+        .collect();
+    git::restore_files(repo, &paths).expect("Error restoring files");
+    if let (Some(file), Some(original)) = (file, original) {
+        fs::write(workdir.join(file), original).expect("Error restoring file");
+    }
+    (result.is_ok(), contents)
+}
+
+/// Keep contents of files changed by a command as changed pages
+fn add_exec_pages(dir: &mut structs::Directory, workdir: &Path, changed: Vec<(PathBuf, Vec<u8>)>) {
+    changed.into_iter().for_each(|(f, content)| {
+        let Ok(content) = String::from_utf8(content) else {
+            println!(
+                "  Skipping {}, command output is not valid UTF-8",
+                f.display()
+            );
+            dir.skipped_files
+                .push((dir.relative_path.join(&f), structs::SkipReason::Binary));
+            return;
+        };
+        println!("  Changed {}", f.display());
+        let path = workdir.join(&f);
+        let page = match dir.pages.iter().position(|p| p.path == path) {
+            Some(i) => &mut dir.pages[i],
+            None => {
+                dir.pages
+                    .push(structs::Page::new(path, dir.relative_path.join(&f)));
+                dir.pages.last_mut().expect("Error adding page")
+            }
+        };
+        page.changes = true;
+        page.content = Some(content);
+    });
+}
+
+/// Get full reference of a selected tag, `None` for branches
+fn tag_ref(repo: &git2::Repository, name: &str) -> Option<String> {
+    (name.starts_with("tags/") && repo.find_branch(name, git2::BranchType::Local).is_err())
+        .then(|| format!("refs/{name}"))
+}
+
+/// Pick a single branch or tag out of several matching ones
+fn pick_branch(
+    repo: &git2::Repository,
+    names: Vec<String>,
+    pick: structs::BranchPick,
+) -> Option<String> {
+    let time = |name: &String| {
+        let refname = tag_ref(repo, name).unwrap_or_else(|| format!("refs/heads/{name}"));
+        git::ref_time(repo, &refname).unwrap_or(i64::MIN)
+    };
+    match pick {
+        structs::BranchPick::Newest => names.into_iter().max_by_key(time),
+        structs::BranchPick::Oldest => names.into_iter().min_by_key(time),
+        structs::BranchPick::SemverHighest => {
+            names.into_iter().max_by_key(|name| func::version_key(name))
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    /// Create a repository `repo` with a committed `values.yaml` in a fresh directory,
+    /// returning the directory and repository
+    fn scratch_repo(name: &str) -> (PathBuf, git2::Repository) {
+        let root =
+            std::env::temp_dir().join(format!("gitraider-test-{name}-{}", std::process::id()));
+        fs::remove_dir_all(&root).ok();
+        let path = root.join("repo");
+        fs::create_dir_all(&path).unwrap();
+        fs::write(path.join("values.yaml"), "host: prod\n").unwrap();
+
+        let repo = git2::Repository::init(&path).unwrap();
+        let mut config = repo.config().unwrap();
+        config.set_str("user.name", "Test").unwrap();
+        config.set_str("user.email", "test@example.com").unwrap();
+        config.set_bool("commit.gpgsign", false).unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("values.yaml")).unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = repo.signature().unwrap();
+        repo.commit(Some("HEAD"), &signature, &signature, "init", &tree, &[])
+            .unwrap();
+        drop(tree);
+        (root, repo)
+    }
+
+    /// Create branches at HEAD
+    fn add_branches(repo: &git2::Repository, names: &[&str]) {
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        names.iter().for_each(|name| {
+            repo.branch(name, &head, false).unwrap();
+        });
+    }
+
+    /// Find repositories under root and replace `prod` with `stage` in `values.yaml`
+    /// on each selected branch, committing changes
+    fn raid(raider: &mut RepoRaider, pattern: Option<&str>) {
+        raider.find_repos();
+        raider.select_branches(pattern, None);
+        while raider.next_branch() {
+            raider.match_files("values");
+            raider.match_lines("prod");
+            raider.replace("prod", "stage", false);
+            raider.apply();
+            raider.stage();
+            raider.commit("Use stage", &sign::SignOptions::default(), false);
+        }
+    }
+
+    /// Content of `values.yaml` on a branch
+    fn branch_values(repo: &git2::Repository, branch: &str) -> String {
+        let tree = repo
+            .revparse_single(&format!("refs/heads/{branch}"))
+            .unwrap()
+            .peel_to_tree()
+            .unwrap();
+        let blob = tree
+            .get_path(Path::new("values.yaml"))
+            .unwrap()
+            .to_object(repo)
+            .unwrap();
+        String::from_utf8(blob.as_blob().unwrap().content().to_vec()).unwrap()
+    }
+
+    #[test]
+    fn raider_multiple_branches() {
+        let (root, repo) = scratch_repo("branches");
+        add_branches(&repo, &["release/1", "release/2"]);
+
+        let mut raider = RepoRaider::new(root.display().to_string(), false);
+        raid(&mut raider, Some("release/.*"));
+
+        assert_eq!(branch_values(&repo, "release/1"), "host: stage\n");
+        assert_eq!(branch_values(&repo, "release/2"), "host: stage\n");
+        let history = &raider.dirs[0].history;
+        let branches: Vec<&str> = history.iter().map(|r| r.branch.as_str()).collect();
+        assert_eq!(branches, vec!["release/1", "release/2"]);
+        assert!(history
+            .iter()
+            .all(|r| r.committed && r.pages.iter().any(|p| p.changes)));
+        drop(raider);
+        fs::remove_dir_all(root).ok();
+    }
+
+    #[test]
+    fn raider_commit_staged() {
+        let (root, repo) = scratch_repo("staged");
+        fs::write(root.join("repo/mine.txt"), "mine\n").unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("mine.txt")).unwrap();
+        index.write().unwrap();
+
+        // Replace and stage, then commit in a separate run
+        let mut raider = RepoRaider::new(root.display().to_string(), false);
+        raider.find_repos();
+        raider.select_branches(None, None);
+        raider.next_branch();
+        raider.match_files("values");
+        raider.match_lines("prod");
+        raider.replace("prod", "stage", false);
+        raider.apply();
+        raider.stage();
+        drop(raider);
+
+        let mut raider = RepoRaider::new(root.display().to_string(), false);
+        raider.find_repos();
+        raider.find_staged();
+        assert_eq!(raider.dirs[0].pages.len(), 1);
+        raider.commit("Use stage", &sign::SignOptions::default(), false);
+
+        // File staged by the user stays staged and out of the commit
+        let tree = repo.head().unwrap().peel_to_tree().unwrap();
+        assert!(tree.get_name("mine.txt").is_none());
+        let values = tree
+            .get_name("values.yaml")
+            .unwrap()
+            .to_object(&repo)
+            .unwrap();
+        assert_eq!(values.as_blob().unwrap().content(), b"host: stage\n");
+        let staged = git::staged_files(&repo).unwrap();
+        assert_eq!(staged, vec![PathBuf::from("mine.txt")]);
+        assert!(git::recorded_staged(&repo).is_empty());
+        drop(tree);
+        drop(raider);
+        fs::remove_dir_all(root).ok();
+    }
+
+    #[test]
+    fn raider_retain_unpushed() {
+        let (root, repo) = scratch_repo("unpushed");
+        let origin = root.join("origin.git");
+        git2::Repository::init_bare(&origin).unwrap();
+        repo.remote("origin", origin.to_str().unwrap()).unwrap();
+        let branch = git::get_branch_name(&repo).unwrap();
+        let head = repo.head().unwrap().target().unwrap();
+        repo.reference(&format!("refs/remotes/origin/{branch}"), head, true, "test")
+            .unwrap();
+        let mut config = repo.config().unwrap();
+        config
+            .set_str(&format!("branch.{branch}.remote"), "origin")
+            .unwrap();
+        config
+            .set_str(
+                &format!("branch.{branch}.merge"),
+                &format!("refs/heads/{branch}"),
+            )
+            .unwrap();
+
+        let mut raider = RepoRaider::new(root.display().to_string(), false);
+        raid(&mut raider, None);
+        drop(raider);
+        let unpushed = |path: &Path| {
+            let mut raider = RepoRaider::new(path.display().to_string(), false);
+            raider.find_repos();
+            raider.retain_unpushed();
+            raider.dirs.len()
+        };
+        assert_eq!(unpushed(&root), 1);
+
+        // Commit not made by gitraider is not pushed
+        let parent = repo.head().unwrap().peel_to_commit().unwrap();
+        let tree = parent.tree().unwrap();
+        let signature = repo.signature().unwrap();
+        repo.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            "mine",
+            &tree,
+            &[&parent],
+        )
+        .unwrap();
+        assert_eq!(unpushed(&root), 0);
+        drop((tree, parent));
+        fs::remove_dir_all(root).ok();
+    }
+
+    #[test]
+    fn raider_filter_remote_url() {
+        let (root, repo) = scratch_repo("remote-url");
+        repo.remote("upstream", "https://example.com/team/repo.git")
+            .unwrap();
+        let filter = filter::RepoFilter::new(None, Some("example.com/team/"), &[], None);
+        let filtered = |remote: &str| {
+            let mut raider = RepoRaider::new(root.display().to_string(), true);
+            raider.remote = remote.to_string();
+            raider.find_repos();
+            raider.filter_repos(&filter);
+            (raider.dirs.len(), std::mem::take(&mut raider.skipped))
+        };
+
+        assert_eq!(filtered("upstream").0, 1);
+        let (kept, skipped) = filtered("origin");
+        assert_eq!(kept, 0);
+        assert_eq!(skipped[0].1, "no origin remote");
+        fs::remove_dir_all(root).ok();
+    }
+
+    #[test]
+    fn raider_count_changes() {
+        let (root, repo) = scratch_repo("count");
+        add_branches(&repo, &["release/1", "release/2"]);
+
+        let mut raider = RepoRaider::new(root.display().to_string(), false);
+        raider.find_repos();
+        raider.select_branches(Some("release/.*"), None);
+        let limits = structs::Limits {
+            max_changes_per_file: Some(0),
+            max_files_per_repo: None,
+            max_repos: Some(1),
+        };
+        let mut totals = structs::RunTotals::default();
+        while raider.next_branch() {
+            raider.match_files("values");
+            raider.match_lines("prod");
+            raider.replace("prod", "stage", false);
+            raider.count_changes(&mut totals, &limits);
+        }
+
+        // Repository changed on both branches is counted once
+        assert_eq!((totals.repos.len(), totals.files, totals.lines), (1, 2, 2));
+        assert_eq!(totals.check(&limits).unwrap_err().len(), 2);
+        let limits = structs::Limits {
+            max_repos: Some(0),
+            ..structs::Limits::default()
+        };
+        let exceeded = totals.check(&limits).unwrap_err();
+        assert_eq!(
+            exceeded.last().unwrap(),
+            "1 repositories changed, limit is 0"
+        );
+        drop(raider);
+        fs::remove_dir_all(root).ok();
+    }
+
+    #[test]
+    fn raider_restores_head() {
+        let (root, repo) = scratch_repo("restore");
+        add_branches(&repo, &["release/1"]);
+        let original = git::head_ref(&repo).unwrap();
+
+        let mut raider = RepoRaider::new(root.display().to_string(), false);
+        raid(&mut raider, Some("release/1"));
+        assert_eq!(git::head_ref(&repo).unwrap(), "refs/heads/release/1");
+        drop(raider);
+
+        assert_eq!(git::head_ref(&repo).unwrap(), original);
+        fs::remove_dir_all(root).ok();
+    }
+
+    #[test]
+    fn raider_restores_head_after_failed_checkout() {
+        let (root, repo) = scratch_repo("failed");
+        add_branches(&repo, &["release/1", "release/2"]);
+        let original = git::head_ref(&repo).unwrap();
+
+        // Untracked file conflicting with a file committed on release/2
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        let mut tree = repo.treebuilder(Some(&head.tree().unwrap())).unwrap();
+        let blob = repo.blob(b"committed\n").unwrap();
+        tree.insert("extra.yaml", blob, 0o100_644).unwrap();
+        let tree = repo.find_tree(tree.write().unwrap()).unwrap();
+        let signature = repo.signature().unwrap();
+        repo.commit(
+            Some("refs/heads/release/2"),
+            &signature,
+            &signature,
+            "extra",
+            &tree,
+            &[&head],
+        )
+        .unwrap();
+        fs::write(root.join("repo/extra.yaml"), "untracked\n").unwrap();
+
+        let mut raider = RepoRaider::new(root.display().to_string(), false);
+        raid(&mut raider, Some("release/.*"));
+        let history = &raider.dirs[0].history;
+        assert!(history[0].committed && history[0].failed.is_none());
+        assert!(history[1].failed.is_some());
+        drop(raider);
+
+        assert_eq!(branch_values(&repo, "release/1"), "host: stage\n");
+        assert_eq!(git::head_ref(&repo).unwrap(), original);
+        fs::remove_dir_all(root).ok();
+    }
+
+    #[test]
+    fn raider_restores_head_on_panic() {
+        let (root, repo) = scratch_repo("panic");
+        add_branches(&repo, &["release/1"]);
+        let original = git::head_ref(&repo).unwrap();
+
+        let path = root.display().to_string();
+        let result = std::panic::catch_unwind(move || {
+            let mut raider = RepoRaider::new(path, false);
+            raider.find_repos();
+            raider.select_branches(Some("release/1"), None);
+            raider.next_branch();
+            panic!("ERROR: Failure after checkout");
+        });
+
+        assert!(result.is_err());
+        assert_eq!(git::head_ref(&repo).unwrap(), original);
+        fs::remove_dir_all(root).ok();
+    }
+
+    #[test]
+    fn raider_keeps_dirty_repo() {
+        let (root, repo) = scratch_repo("dirty");
+        add_branches(&repo, &["release/1"]);
+
+        let mut raider = RepoRaider::new(root.display().to_string(), false);
+        raider.find_repos();
+        raider.select_branches(Some("release/1"), None);
+        raider.next_branch();
+        raider.match_files("values");
+        raider.match_lines("prod");
+        raider.replace("prod", "stage", false);
+        raider.apply();
+        drop(raider);
+
+        // Uncommitted changes stay on the checked out branch
+        assert_eq!(git::head_ref(&repo).unwrap(), "refs/heads/release/1");
+        let values = fs::read_to_string(root.join("repo/values.yaml")).unwrap();
+        assert_eq!(values, "host: stage\n");
+        fs::remove_dir_all(root).ok();
+    }
+
+    #[test]
+    fn raider_restores_repo_dirty_before() {
+        let (root, repo) = scratch_repo("dirty-before");
+        fs::write(root.join("repo/notes.txt"), "notes\n").unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("notes.txt")).unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let parent = repo.head().unwrap().peel_to_commit().unwrap();
+        let signature = repo.signature().unwrap();
+        repo.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            "notes",
+            &tree,
+            &[&parent],
+        )
+        .unwrap();
+        drop((tree, parent));
+        add_branches(&repo, &["release/1"]);
+        let original = git::head_ref(&repo).unwrap();
+        fs::write(root.join("repo/notes.txt"), "edited\n").unwrap();
+
+        let mut raider = RepoRaider::new(root.display().to_string(), false);
+        raid(&mut raider, Some("release/1"));
+        drop(raider);
+
+        // Changes made before the run are carried back to the original branch
+        assert_eq!(branch_values(&repo, "release/1"), "host: stage\n");
+        assert_eq!(git::head_ref(&repo).unwrap(), original);
+        let notes = fs::read_to_string(root.join("repo/notes.txt")).unwrap();
+        assert_eq!(notes, "edited\n");
+        let values = fs::read_to_string(root.join("repo/values.yaml")).unwrap();
+        assert_eq!(values, "host: prod\n");
+        fs::remove_dir_all(root).ok();
+    }
+
+    #[test]
+    fn raider_checkout_conflict() {
+        let (root, repo) = scratch_repo("conflict");
+        add_branches(&repo, &["release/1"]);
+        let mut raider = RepoRaider::new(root.display().to_string(), false);
+        raid(&mut raider, Some("release/1"));
+        drop(raider);
+        let original = git::head_ref(&repo).unwrap();
+        fs::write(root.join("repo/values.yaml"), "host: edited\n").unwrap();
+
+        let mut raider = RepoRaider::new(root.display().to_string(), false);
+        raider.find_repos();
+        raider.select_branches(Some("release/1"), None);
+        assert!(!raider.next_branch());
+
+        // Nothing was checked out, so there is nothing to restore
+        assert!(raider.dirs[0].history[0].failed.is_some());
+        assert!(raider.dirs[0].original_head.is_none());
+        drop(raider);
+        assert_eq!(git::head_ref(&repo).unwrap(), original);
+        let values = fs::read_to_string(root.join("repo/values.yaml")).unwrap();
+        assert_eq!(values, "host: edited\n");
+        fs::remove_dir_all(root).ok();
+    }
+
+    #[test]
+    fn raider_exec_per_file() {
+        let (root, repo) = scratch_repo("exec");
+
+        let mut raider = RepoRaider::new(root.display().to_string(), false);
+        raider.find_repos();
+        raider.select_branches(None, None);
+        raider.next_branch();
+        raider.match_files("values");
+        raider.match_lines("prod");
+        raider.exec(
+            "echo changed >> other.txt; echo 'host: stage' > {}",
+            false,
+            true,
+        );
+
+        // Files changed by the command are restored until apply
+        assert!(!root.join("repo/other.txt").exists());
+        let values = fs::read_to_string(root.join("repo/values.yaml")).unwrap();
+        assert_eq!(values, "host: prod\n");
+        let changed: Vec<&str> = raider.dirs[0]
+            .pages
+            .iter()
+            .filter(|p| p.changes)
+            .map(|p| p.path.file_name().unwrap().to_str().unwrap())
+            .collect();
+        assert_eq!(changed, vec!["values.yaml", "other.txt"]);
+
+        raider.apply();
+        raider.stage();
+        raider.commit("Use stage", &sign::SignOptions::default(), false);
+        let tree = repo.head().unwrap().peel_to_tree().unwrap();
+        assert!(tree.get_name("other.txt").is_some());
+        assert!(!git::is_dirty(&repo).unwrap());
+        drop(tree);
+        drop(raider);
+        fs::remove_dir_all(root).ok();
+    }
+
+    #[test]
+    fn raider_validate_rollback() {
+        let (root, repo) = scratch_repo("validate");
+
+        let mut raider = RepoRaider::new(root.display().to_string(), false);
+        raider.find_repos();
+        raider.select_branches(None, None);
+        raider.next_branch();
+        raider.match_files("values");
+        raider.match_lines("prod");
+        raider.replace("prod", "stage", false);
+        raider.exec("echo created > new.yaml", true, false);
+        raider.apply();
+        raider.stage();
+        assert!(root.join("repo/new.yaml").exists());
+
+        // Failing check rolls back changed and created files
+        raider.validate(false, Some("exit 1"), None);
+        assert!(raider.dirs[0].failed.is_some());
+        let values = fs::read_to_string(root.join("repo/values.yaml")).unwrap();
+        assert_eq!(values, "host: prod\n");
+        assert!(!root.join("repo/new.yaml").exists());
+        assert!(!git::is_dirty(&repo).unwrap());
+
+        // Failed repositories are not committed
+        let head = repo.head().unwrap().target();
+        raider.commit("Use stage", &sign::SignOptions::default(), false);
+        assert_eq!(repo.head().unwrap().target(), head);
+        drop(raider);
+        fs::remove_dir_all(root).ok();
+    }
+
+    #[test]
+    fn raider_worktree() {
+        let (root, repo) = scratch_repo("worktree");
+        add_branches(&repo, &["release/1"]);
+        let original = git::head_ref(&repo).unwrap();
+
+        let mut raider = RepoRaider::new(root.display().to_string(), false);
+        raider.worktree = true;
+        raider.find_repos();
+        raider.select_branches(Some("release/1"), None);
+        assert!(raider.next_branch());
+        let path = raider.dirs[0].path.clone();
+        assert!(path.starts_with(std::env::temp_dir()) && path.exists());
+        assert_eq!(repo.worktrees().unwrap().len(), 1);
+        raider.match_files("values");
+        raider.match_lines("prod");
+        raider.replace("prod", "stage", false);
+        raider.apply();
+        raider.stage();
+        raider.commit("Use stage", &sign::SignOptions::default(), false);
+        assert!(!raider.next_branch());
+
+        // Commit is fast-forwarded to the branch and the worktree is removed
+        assert_eq!(branch_values(&repo, "release/1"), "host: stage\n");
+        assert!(!path.exists());
+        assert_eq!(repo.worktrees().unwrap().len(), 0);
+        assert_eq!(repo.branches(None).unwrap().count(), 2);
+        assert_eq!(git::head_ref(&repo).unwrap(), original);
+        let values = fs::read_to_string(root.join("repo/values.yaml")).unwrap();
+        assert_eq!(values, "host: prod\n");
+        drop(raider);
+        fs::remove_dir_all(root).ok();
+    }
+
+    #[test]
+    fn raider_worktree_branch_placeholder() {
+        let (root, repo) = scratch_repo("worktree-placeholder");
+        add_branches(&repo, &["release/1"]);
+        let head = repo.head().unwrap().target().unwrap();
+        repo.tag_lightweight("v1", &repo.find_object(head, None).unwrap(), false)
+            .unwrap();
+
+        let mut raider = RepoRaider::new(root.display().to_string(), false);
+        raider.worktree = true;
+        raider.find_repos();
+        raider.select_branches(Some("release/1"), None);
+        assert!(raider.next_branch());
+        raider.match_files("values");
+        raider.match_lines("prod");
+        raider.replace("prod", "${branch}", false);
+        raider.apply();
+        raider.stage();
+        raider.commit("Use branch", &sign::SignOptions::default(), false);
+        assert!(!raider.next_branch());
+        assert_eq!(branch_values(&repo, "release/1"), "host: release/1\n");
+        drop(raider);
+
+        // Tags are checked out detached, placeholder is the tag name
+        let mut raider = RepoRaider::new(root.display().to_string(), true);
+        raider.find_repos();
+        raider.select_branches(Some("tag:v1"), None);
+        assert!(raider.next_branch());
+        raider.match_files("values");
+        raider.match_lines("prod");
+        raider.replace("prod", "${branch}", false);
+        let replace = raider.dirs[0].pages[0].matches[0].replace.clone();
+        assert_eq!(replace.as_deref(), Some("host: v1"));
+        drop(raider);
+        fs::remove_dir_all(root).ok();
+    }
+
+    #[test]
+    fn raider_worktree_current_branch() {
+        let (root, repo) = scratch_repo("worktree-current");
+        let original = git::head_ref(&repo).unwrap();
+        let branch = original.strip_prefix("refs/heads/").unwrap().to_string();
+
+        let mut raider = RepoRaider::new(root.display().to_string(), false);
+        raider.worktree = true;
+        raid(&mut raider, None);
+        assert!(raider.dirs[0].history[0].committed);
+        drop(raider);
+
+        // Checked out branch is left alone, keeping the worktree with the unpushed commit
+        assert_eq!(git::head_ref(&repo).unwrap(), original);
+        assert_eq!(branch_values(&repo, &branch), "host: prod\n");
+        let names = repo.worktrees().unwrap();
+        assert_eq!(names.len(), 1);
+        let name = names.get(0).unwrap();
+        assert_eq!(branch_values(&repo, name), "host: stage\n");
+        let path = repo.find_worktree(name).unwrap().path().to_path_buf();
+        git::remove_worktree(&repo, name).unwrap();
+        assert!(!path.exists());
+        fs::remove_dir_all(root).ok();
+    }
+
     #[test]
     fn raider_new() {
         let path = "./".to_string();
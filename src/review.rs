@@ -0,0 +1,196 @@
+use std::fs;
+use std::io::{self, Write};
+
+use crate::raider::RepoRaider;
+use crate::structs::Page;
+
+/// Decision on a proposed replacement
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Decision {
+    Accept,
+    Reject,
+    Edit(String),
+    AcceptFile,
+    SkipRepo,
+}
+
+impl Decision {
+    /// Parse answer to review prompt
+    #[must_use]
+    pub fn parse(answer: &str) -> Option<Self> {
+        match answer.trim().to_lowercase().as_str() {
+            "y" | "yes" => Some(Self::Accept),
+            "n" | "no" => Some(Self::Reject),
+            "e" | "edit" => Some(Self::Edit(String::new())),
+            "a" | "all" => Some(Self::AcceptFile),
+            "s" | "skip" => Some(Self::SkipRepo),
+            _ => None,
+        }
+    }
+}
+
+/// Number of lines shown before and after a proposed replacement
+const CONTEXT_LINES: usize = 2;
+
+/// Review proposed replacements repo by repo, asking for a decision on each.
+/// Contents produced by a command are reviewed as a diff per file.
+/// Rejected replacements are dropped so that apply only writes approved ones
+pub fn review(raider: &mut RepoRaider) {
+    for dir in &mut raider.dirs {
+        if dir.failed.is_some() || !dir.pages.iter().any(has_replacements) {
+            continue;
+        }
+        println!("\nRepo {}", dir.relative_path.display());
+
+        let mut skip_repo = false;
+        for page in dir.pages.iter_mut().filter(|p| p.changes) {
+            if skip_repo {
+                page.discard_changes();
+                continue;
+            }
+            if !has_replacements(page) {
+                continue;
+            }
+
+            println!("  {}", page.relative_path.display());
+            let original = fs::read_to_string(&page.path).unwrap_or_default();
+
+            // Review whole file changed by a command
+            if let Some(content) = &page.content {
+                print_diff(&original, content);
+                match ask_file() {
+                    Decision::Reject => page.discard_changes(),
+                    Decision::SkipRepo => {
+                        page.discard_changes();
+                        skip_repo = true;
+                    }
+                    _ => {}
+                }
+                continue;
+            }
+
+            let lines: Vec<String> = original.lines().map(str::to_string).collect();
+
+            let mut accept_file = false;
+            for m in page.matches.iter_mut() {
+                let Some(replace) = m.replace.clone() else {
+                    continue;
+                };
+                if accept_file || replace == m.content {
+                    continue;
+                }
+                if skip_repo {
+                    m.replace = None;
+                    continue;
+                }
+
+                // Show replacement with surrounding lines
+                let line = m.line;
+                let start = line.saturating_sub(CONTEXT_LINES);
+                let end = (line + CONTEXT_LINES + 1).min(lines.len());
+                for (n, content) in lines.iter().enumerate().take(end).skip(start) {
+                    if n == line {
+                        println!("    -{n:>5}: {}", m.content);
+                        println!("    +{n:>5}: {replace}");
+                    } else {
+                        println!("     {n:>5}: {content}");
+                    }
+                }
+
+                match ask() {
+                    Decision::Accept => {}
+                    Decision::Reject => m.replace = None,
+                    Decision::Edit(edited) => m.replace = Some(edited),
+                    Decision::AcceptFile => accept_file = true,
+                    Decision::SkipRepo => {
+                        m.replace = None;
+                        skip_repo = true;
+                    }
+                }
+            }
+
+            // Unmark page if all of its replacements were rejected
+            if page.content.is_none() && page.matches.iter().all(|m| m.replace.is_none()) {
+                page.changes = false;
+            }
+        }
+    }
+}
+
+/// Check if page has replacements or contents produced by a command to review
+fn has_replacements(page: &Page) -> bool {
+    page.changes
+        && (page.content.is_some()
+            || page
+                .matches
+                .iter()
+                .any(|m| m.replace.as_ref().is_some_and(|r| *r != m.content)))
+}
+
+/// Print changed block of lines between unchanged start and end of file, with context
+fn print_diff(original: &str, changed: &str) {
+    let old: Vec<&str> = original.lines().collect();
+    let new: Vec<&str> = changed.lines().collect();
+    let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let start = prefix.saturating_sub(CONTEXT_LINES);
+    (start..prefix).for_each(|n| println!("     {n:>5}: {}", old[n]));
+    (prefix..old.len() - suffix).for_each(|n| println!("    -{n:>5}: {}", old[n]));
+    (prefix..new.len() - suffix).for_each(|n| println!("    +{n:>5}: {}", new[n]));
+    let end = (old.len() - suffix + CONTEXT_LINES).min(old.len());
+    (old.len() - suffix..end).for_each(|n| println!("     {n:>5}: {}", old[n]));
+    if prefix == old.len() && prefix == new.len() {
+        println!("    Line endings or trailing newline changed");
+    }
+}
+
+/// Ask for a decision on stdin until a valid answer is given.
+/// End of input rejects the replacement
+fn ask() -> Decision {
+    loop {
+        print!("    Apply? [y]es, [n]o, [e]dit, [a]ll in file, [s]kip repo: ");
+        let Some(answer) = read_line() else {
+            return Decision::Reject;
+        };
+        match Decision::parse(&answer) {
+            Some(Decision::Edit(_)) => {
+                print!("    New line: ");
+                return read_line().map_or(Decision::Reject, Decision::Edit);
+            }
+            Some(decision) => return decision,
+            None => println!("    Unknown answer '{}'", answer.trim()),
+        }
+    }
+}
+
+/// Ask for a decision on a whole file until a valid answer is given.
+/// End of input rejects the changes
+fn ask_file() -> Decision {
+    loop {
+        print!("    Apply? [y]es, [n]o, [s]kip repo: ");
+        let Some(answer) = read_line() else {
+            return Decision::Reject;
+        };
+        match Decision::parse(&answer) {
+            Some(Decision::Accept | Decision::AcceptFile) => return Decision::Accept,
+            Some(decision @ (Decision::Reject | Decision::SkipRepo)) => return decision,
+            _ => println!("    Unknown answer '{}'", answer.trim()),
+        }
+    }
+}
+
+/// Read a line from stdin without line ending, `None` at end of input
+fn read_line() -> Option<String> {
+    io::stdout().flush().expect("Error flushing stdout");
+    let mut line = String::new();
+    match io::stdin().read_line(&mut line) {
+        Ok(0) | Err(_) => None,
+        Ok(_) => Some(line.trim_end_matches(['\r', '\n']).to_string()),
+    }
+}
@@ -0,0 +1,354 @@
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use clap::ValueEnum;
+use git2::Repository;
+
+/// Signature format, follows git's `gpg.format` convention
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum SignFormat {
+    Openpgp,
+    Ssh,
+    X509,
+}
+
+/// Signing settings passed from the command line.
+/// Unset values are read from the repo's git config
+#[derive(Clone, Debug, Default)]
+pub struct SignOptions {
+    pub enabled: Option<bool>,
+    pub key: Option<String>,
+    pub format: Option<SignFormat>,
+}
+
+/// Commit signer resolved for a specific repository
+#[derive(Clone, Debug)]
+pub struct Signer {
+    pub format: SignFormat,
+    pub key: Option<String>,
+    pub program: String,
+}
+
+impl Signer {
+    /// Resolve signer from repo's git config and command line overrides.
+    /// Returns `None` if commits should not be signed
+    pub fn from_config(repo: &Repository, opts: &SignOptions) -> Result<Option<Self>, git2::Error> {
+        let config = repo.config()?;
+
+        let enabled = opts
+            .enabled
+            .unwrap_or_else(|| config.get_bool("commit.gpgsign").unwrap_or(false));
+        if !enabled {
+            return Ok(None);
+        }
+
+        let format = match opts.format {
+            Some(format) => format,
+            None => match config.get_string("gpg.format").ok().as_deref() {
+                None | Some("openpgp") => SignFormat::Openpgp,
+                Some("ssh") => SignFormat::Ssh,
+                Some("x509") => SignFormat::X509,
+                Some(other) => {
+                    return Err(git2::Error::from_str(&format!(
+                        "Unsupported gpg.format '{other}'"
+                    )))
+                }
+            },
+        };
+
+        let key = opts
+            .key
+            .clone()
+            .or_else(|| config.get_string("user.signingkey").ok());
+
+        // Program lookup order matches git's
+        let program = match format {
+            SignFormat::Openpgp => config
+                .get_string("gpg.openpgp.program")
+                .or_else(|_| config.get_string("gpg.program"))
+                .unwrap_or_else(|_| "gpg".to_string()),
+            SignFormat::Ssh => config
+                .get_string("gpg.ssh.program")
+                .unwrap_or_else(|_| "ssh-keygen".to_string()),
+            SignFormat::X509 => config
+                .get_string("gpg.x509.program")
+                .unwrap_or_else(|_| "gpgsm".to_string()),
+        };
+
+        Ok(Some(Self {
+            format,
+            key,
+            program,
+        }))
+    }
+
+    /// Sign a commit buffer and return an armored signature
+    pub fn sign(&self, buffer: &str, committer: &str) -> Result<String, git2::Error> {
+        match self.format {
+            SignFormat::Openpgp | SignFormat::X509 => {
+                // Without an explicit key gpg picks one by committer identity
+                let key = self.key.as_deref().unwrap_or(committer);
+                run_signer(
+                    Command::new(&self.program).args(["--status-fd=2", "-bsau", key]),
+                    buffer,
+                )
+            }
+            SignFormat::Ssh => {
+                let key = self
+                    .key
+                    .as_deref()
+                    .ok_or_else(|| git2::Error::from_str("No SSH signing key configured"))?;
+
+                // Literal public keys are written to a temporary file
+                // and the private key is looked up in ssh-agent
+                let literal = key.strip_prefix("key::").or_else(|| {
+                    if key.starts_with("ssh-") {
+                        Some(key)
+                    } else {
+                        None
+                    }
+                });
+                let key_file = match literal {
+                    Some(public_key) => write_temp_key(public_key)?,
+                    None => expand_home(key),
+                };
+
+                let mut command = Command::new(&self.program);
+                command.args(["-Y", "sign", "-n", "git", "-f"]);
+                command.arg(&key_file);
+                if literal.is_some() {
+                    command.arg("-U");
+                }
+                let signature = run_signer(&mut command, buffer);
+
+                if literal.is_some() {
+                    fs::remove_file(&key_file).ok();
+                }
+                signature
+            }
+        }
+    }
+}
+
+/// Run signing program feeding buffer through stdin and return its stdout
+fn run_signer(command: &mut Command, buffer: &str) -> Result<String, git2::Error> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| git2::Error::from_str(&format!("Error starting signing program: {e}")))?;
+
+    child
+        .stdin
+        .take()
+        .expect("Error unwrapping signer stdin")
+        .write_all(buffer.as_bytes())
+        .map_err(|e| git2::Error::from_str(&format!("Error writing to signing program: {e}")))?;
+
+    let output = child
+        .wait_with_output()
+        .map_err(|e| git2::Error::from_str(&format!("Error running signing program: {e}")))?;
+
+    if !output.status.success() {
+        return Err(git2::Error::from_str(&format!(
+            "Signing failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    String::from_utf8(output.stdout)
+        .map_err(|_| git2::Error::from_str("Signature is not valid UTF-8"))
+}
+
+/// Write a literal public key to a newly created temporary file.
+/// Files are created exclusively, never writing through a file or link placed there by others
+fn write_temp_key(public_key: &str) -> Result<PathBuf, git2::Error> {
+    let error =
+        |e: std::io::Error| git2::Error::from_str(&format!("Error writing signing key: {e}"));
+    for n in 0..100 {
+        let name = format!("gitraider-signing-{}-{n}.pub", std::process::id());
+        let path = std::env::temp_dir().join(name);
+        match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+        {
+            Ok(mut file) => {
+                file.write_all(public_key.as_bytes()).map_err(error)?;
+                return Ok(path);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(error(e)),
+        }
+    }
+    Err(git2::Error::from_str(
+        "Error writing signing key: no free temporary file name",
+    ))
+}
+
+/// Expand leading `~/` to user's home directory
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), std::env::var_os("HOME")) {
+        (Some(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    /// Create a repository in a fresh directory with local config entries
+    fn scratch_repo(name: &str, entries: &[(&str, &str)]) -> (PathBuf, Repository) {
+        let root =
+            std::env::temp_dir().join(format!("gitraider-sign-{name}-{}", std::process::id()));
+        fs::remove_dir_all(&root).ok();
+        let repo = Repository::init(&root).unwrap();
+        let mut config = repo
+            .config()
+            .unwrap()
+            .open_level(git2::ConfigLevel::Local)
+            .unwrap();
+        entries
+            .iter()
+            .for_each(|(key, value)| config.set_str(key, value).unwrap());
+        (root, repo)
+    }
+
+    fn options(enabled: Option<bool>, format: Option<SignFormat>) -> SignOptions {
+        SignOptions {
+            enabled,
+            key: None,
+            format,
+        }
+    }
+
+    #[test]
+    fn signer_enabled() {
+        let (root, repo) = scratch_repo("enabled", &[("commit.gpgsign", "false")]);
+        assert!(Signer::from_config(&repo, &options(None, None))
+            .unwrap()
+            .is_none());
+        assert!(Signer::from_config(&repo, &options(Some(true), None))
+            .unwrap()
+            .is_some());
+
+        let (root_signed, signed) = scratch_repo(
+            "signed",
+            &[("commit.gpgsign", "true"), ("user.signingkey", "ABCD")],
+        );
+        let signer = Signer::from_config(&signed, &options(None, None))
+            .unwrap()
+            .unwrap();
+        assert_eq!(signer.key.as_deref(), Some("ABCD"));
+        assert!(Signer::from_config(&signed, &options(Some(false), None))
+            .unwrap()
+            .is_none());
+
+        // Key passed on the command line overrides git config
+        let mut opts = options(None, None);
+        opts.key = Some("EF01".to_string());
+        let signer = Signer::from_config(&signed, &opts).unwrap().unwrap();
+        assert_eq!(signer.key.as_deref(), Some("EF01"));
+
+        fs::remove_dir_all(root).ok();
+        fs::remove_dir_all(root_signed).ok();
+    }
+
+    #[test]
+    fn signer_format_and_program() {
+        let (root, repo) = scratch_repo(
+            "format",
+            &[
+                ("commit.gpgsign", "true"),
+                ("gpg.format", "ssh"),
+                ("gpg.ssh.program", "my-ssh-keygen"),
+                ("gpg.program", "gpg2"),
+                ("gpg.x509.program", "my-gpgsm"),
+            ],
+        );
+        let signer = Signer::from_config(&repo, &options(None, None))
+            .unwrap()
+            .unwrap();
+        assert_eq!(signer.format, SignFormat::Ssh);
+        assert_eq!(signer.program, "my-ssh-keygen");
+
+        // Format flag overrides gpg.format, gpg.program is used without gpg.openpgp.program
+        let openpgp = options(None, Some(SignFormat::Openpgp));
+        let signer = Signer::from_config(&repo, &openpgp).unwrap().unwrap();
+        assert_eq!(signer.format, SignFormat::Openpgp);
+        assert_eq!(signer.program, "gpg2");
+        let mut config = repo
+            .config()
+            .unwrap()
+            .open_level(git2::ConfigLevel::Local)
+            .unwrap();
+        config.set_str("gpg.openpgp.program", "my-gpg").unwrap();
+        let signer = Signer::from_config(&repo, &openpgp).unwrap().unwrap();
+        assert_eq!(signer.program, "my-gpg");
+
+        let x509 = options(None, Some(SignFormat::X509));
+        let signer = Signer::from_config(&repo, &x509).unwrap().unwrap();
+        assert_eq!(signer.program, "my-gpgsm");
+
+        config.set_str("gpg.format", "pgp").unwrap();
+        assert!(Signer::from_config(&repo, &options(None, None)).is_err());
+        fs::remove_dir_all(root).ok();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn signer_ssh_keys() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // Stub printing the key file, its content and whether -U was passed
+        let (root, repo) = scratch_repo("ssh", &[]);
+        let stub = root.join("ssh-keygen");
+        fs::write(
+            &stub,
+            "#!/bin/sh\ncat > /dev/null\necho \"$6\"\ncat \"$6\" 2>/dev/null\necho \"|$7\"\n",
+        )
+        .unwrap();
+        fs::set_permissions(&stub, fs::Permissions::from_mode(0o755)).unwrap();
+        let mut signer = Signer {
+            format: SignFormat::Ssh,
+            key: None,
+            program: stub.display().to_string(),
+        };
+        assert!(signer.sign("buffer", "Test").is_err());
+
+        for literal in ["key::ssh-ed25519 AAAA test", "ssh-ed25519 AAAA test"] {
+            signer.key = Some(literal.to_string());
+            let output = signer.sign("buffer", "Test").unwrap();
+            let lines: Vec<&str> = output.lines().collect();
+            assert_eq!(lines[1..], ["ssh-ed25519 AAAA test|-U"]);
+            // Temporary key file is removed after signing
+            assert!(!Path::new(lines[0]).exists());
+        }
+
+        let key = root.join("id_ed25519.pub");
+        signer.key = Some(key.display().to_string());
+        fs::write(&key, "ssh-ed25519 BBBB").unwrap();
+        let output = signer.sign("buffer", "Test").unwrap();
+        let expected = format!("{}\nssh-ed25519 BBBB|\n", key.display());
+        assert_eq!(output, expected);
+        assert!(key.exists());
+
+        drop(repo);
+        fs::remove_dir_all(root).ok();
+    }
+
+    #[test]
+    fn temp_key_exclusive() {
+        let first = write_temp_key("ssh-ed25519 AAAA").unwrap();
+        let second = write_temp_key("ssh-ed25519 BBBB").unwrap();
+        assert_ne!(first, second);
+        assert_eq!(fs::read_to_string(&first).unwrap(), "ssh-ed25519 AAAA");
+        fs::remove_file(first).ok();
+        fs::remove_file(second).ok();
+    }
+}
@@ -1,7 +1,51 @@
+use std::collections::HashSet;
+use std::fmt;
 use std::path::PathBuf;
 use std::rc::Rc;
 
+use clap::ValueEnum;
 use git2::Repository;
+use serde::Serialize;
+
+/// Kind of git repository layout
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RepoKind {
+    /// Repository with a `.git` directory
+    Normal,
+    /// Linked worktree with a `.git` file
+    Worktree,
+    /// Submodule of a superproject
+    Submodule,
+    /// Repository without a working tree
+    Bare,
+}
+
+/// Reason a file was skipped when matching
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SkipReason {
+    /// File contains NUL bytes, is not valid UTF-8 or is marked `-text`
+    Binary,
+    /// File exceeds maximum file size
+    Oversized,
+    /// File is marked `linguist-generated` in .gitattributes
+    Generated,
+    /// File is stored in Git LFS
+    Lfs,
+    /// File uses a filter driver that is not run when staging
+    Filtered,
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Binary => write!(f, "binary"),
+            Self::Oversized => write!(f, "oversized"),
+            Self::Generated => write!(f, "generated"),
+            Self::Lfs => write!(f, "LFS"),
+            Self::Filtered => write!(f, "filtered"),
+        }
+    }
+}
 
 /// Directory struct
 // #[derive(Clone)]
@@ -10,6 +54,154 @@ pub struct Directory {
     pub repo: Option<Repository>,
     pub relative_path: PathBuf,
     pub pages: Vec<Page>,
+    pub kind: RepoKind,
+    /// Path of superproject if directory is a submodule
+    pub superproject: Option<PathBuf>,
+    /// Files skipped when matching with a reason
+    pub skipped_files: Vec<(PathBuf, SkipReason)>,
+    /// Whether a commit was created during the run
+    pub committed: bool,
+    /// Reason changes were rolled back, if any
+    pub failed: Option<String>,
+    /// Branch currently processed
+    pub branch: Option<String>,
+    /// Branches left to process
+    pub branches: Vec<String>,
+    /// Results of branches already processed
+    pub history: Vec<BranchResult>,
+    /// Branch reference or commit id checked out before switching branches
+    pub original_head: Option<String>,
+    /// Uncommitted changes of tracked files before switching branches
+    pub original_changes: Vec<(PathBuf, git2::Status, Option<git2::Oid>)>,
+    /// Temporary worktree the directory is switched to
+    pub worktree: Option<TempWorktree>,
+    /// Commit replaced by amending, expected on remote branch when force pushing
+    pub amended: Option<git2::Oid>,
+}
+
+/// Temporary linked worktree a branch is changed in, keeping the main checkout untouched
+pub struct TempWorktree {
+    /// Name of the worktree and of its temporary branch
+    pub name: String,
+    /// Branch or tag changed in the worktree
+    pub branch: String,
+    /// Path of the main checkout
+    pub path: PathBuf,
+    /// Repository of the main checkout
+    pub repo: Repository,
+}
+
+/// Results of processing one branch of a repository
+#[derive(Debug)]
+pub struct BranchResult {
+    pub branch: String,
+    pub pages: Vec<Page>,
+    pub skipped_files: Vec<(PathBuf, SkipReason)>,
+    pub committed: bool,
+    pub failed: Option<String>,
+}
+
+impl BranchResult {
+    /// Summary of skipped files by reason
+    #[must_use]
+    pub fn skipped_summary(&self) -> Option<String> {
+        skipped_summary(&self.skipped_files)
+    }
+}
+
+impl Directory {
+    #[must_use]
+    pub fn new(path: PathBuf, repo: Option<Repository>, relative_path: PathBuf) -> Self {
+        let kind = match &repo {
+            Some(r) if r.is_bare() => RepoKind::Bare,
+            Some(r) if r.is_worktree() => RepoKind::Worktree,
+            _ => RepoKind::Normal,
+        };
+        Self {
+            path,
+            repo,
+            relative_path,
+            pages: Vec::new(),
+            kind,
+            superproject: None,
+            skipped_files: Vec::new(),
+            committed: false,
+            failed: None,
+            branch: None,
+            branches: Vec::new(),
+            history: Vec::new(),
+            original_head: None,
+            original_changes: Vec::new(),
+            worktree: None,
+            amended: None,
+        }
+    }
+
+    /// Switch directory to a linked worktree, keeping the main checkout to switch back to
+    pub fn enter_worktree(&mut self, name: String, branch: String, repo: Repository) {
+        let path = repo.workdir().expect("Error getting workdir").to_path_buf();
+        self.worktree = Some(TempWorktree {
+            name,
+            branch,
+            path: std::mem::replace(&mut self.path, path),
+            repo: self.repo.replace(repo).expect("Error unwrapping main repo"),
+        });
+    }
+
+    /// Switch directory back to the main checkout, returning the left worktree's name
+    pub fn leave_worktree(&mut self) -> Option<String> {
+        let worktree = self.worktree.take()?;
+        self.path = worktree.path;
+        self.repo = Some(worktree.repo);
+        Some(worktree.name)
+    }
+
+    /// Move results of currently processed branch to history
+    pub fn archive(&mut self) {
+        self.amended = None;
+        if let Some(branch) = self.branch.take() {
+            self.history.push(BranchResult {
+                branch,
+                pages: std::mem::take(&mut self.pages),
+                skipped_files: std::mem::take(&mut self.skipped_files),
+                committed: std::mem::take(&mut self.committed),
+                failed: self.failed.take(),
+            });
+        }
+    }
+
+    /// Summary of skipped files by reason, i.e. `2 binary, 1 oversized`
+    #[must_use]
+    pub fn skipped_summary(&self) -> Option<String> {
+        skipped_summary(&self.skipped_files)
+    }
+
+    /// Check if directory has a working tree that can be modified
+    #[must_use]
+    pub fn has_workdir(&self) -> bool {
+        self.repo.as_ref().is_some_and(|r| r.workdir().is_some())
+    }
+}
+
+/// Summarise skipped files by reason
+fn skipped_summary(skipped_files: &[(PathBuf, SkipReason)]) -> Option<String> {
+    let summary: Vec<String> = [
+        SkipReason::Binary,
+        SkipReason::Oversized,
+        SkipReason::Generated,
+        SkipReason::Lfs,
+        SkipReason::Filtered,
+    ]
+    .iter()
+    .map(|r| (r, skipped_files.iter().filter(|(_, s)| s == r).count()))
+    .filter(|(_, n)| *n > 0)
+    .map(|(r, n)| format!("{n} {r}"))
+    .collect();
+    if summary.is_empty() {
+        None
+    } else {
+        Some(summary.join(", "))
+    }
 }
 
 /// Page struct
@@ -20,15 +212,131 @@ pub struct Page {
     pub relative_path: PathBuf,
     pub changes: bool,
     pub matches: Vec<Match>,
+    /// New file contents produced by an external command
+    pub content: Option<String>,
+    /// Whether file is checked out with CRLF line endings per `eol=crlf` attribute
+    pub crlf: bool,
     // pub dir: Rc<Directory>,
 }
 
+impl Page {
+    #[must_use]
+    pub fn new(path: PathBuf, relative_path: PathBuf) -> Self {
+        Self {
+            path,
+            relative_path,
+            changes: false,
+            matches: Vec::new(),
+            content: None,
+            crlf: false,
+        }
+    }
+
+    /// Drop all proposed changes
+    pub fn discard_changes(&mut self) {
+        self.matches.iter_mut().for_each(|m| m.replace = None);
+        self.content = None;
+        self.changes = false;
+    }
+
+    /// Count changed lines: replaced matches,
+    /// or lines differing from file on disk for contents produced by a command
+    #[must_use]
+    pub fn change_count(&self) -> usize {
+        if !self.changes {
+            return 0;
+        }
+        let replaced = self
+            .matches
+            .iter()
+            .filter(|m| m.replace.as_ref().is_some_and(|r| *r != m.content))
+            .count();
+        match &self.content {
+            Some(content) if replaced == 0 => {
+                let original = std::fs::read_to_string(&self.path).unwrap_or_default();
+                let (old, new): (Vec<_>, Vec<_>) =
+                    (original.lines().collect(), content.lines().collect());
+                old.iter().zip(&new).filter(|(a, b)| a != b).count() + old.len().abs_diff(new.len())
+            }
+            _ => replaced,
+        }
+    }
+}
+
+/// State of a repository reported by status
+#[derive(Clone, Debug, Serialize)]
+pub struct RepoStatus {
+    pub path: PathBuf,
+    /// Checked out branch, or abbreviated commit id if HEAD is detached
+    pub branch: String,
+    pub detached: bool,
+    /// Whether working tree or index has changes, including untracked files
+    pub dirty: bool,
+    /// Commits ahead and behind upstream, `None` without upstream
+    pub ahead: Option<usize>,
+    pub behind: Option<usize>,
+    pub last_commit_date: Option<String>,
+    pub last_commit_author: Option<String>,
+}
+
+/// Fail-safe limits on number of changes, checked before writing any file
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Limits {
+    pub max_changes_per_file: Option<usize>,
+    pub max_files_per_repo: Option<usize>,
+    pub max_repos: Option<usize>,
+}
+
+/// Changes counted across all branches of a run
+#[derive(Clone, Debug, Default)]
+pub struct RunTotals {
+    /// Relative paths of repositories changed on any branch
+    pub repos: HashSet<PathBuf>,
+    pub files: usize,
+    pub lines: usize,
+    /// Exceeded per file and per repository limits
+    pub exceeded: Vec<String>,
+}
+
+impl RunTotals {
+    /// Check counted changes against limits, returning all exceeded limits
+    pub fn check(&self, limits: &Limits) -> Result<(), Vec<String>> {
+        let mut exceeded = self.exceeded.clone();
+        if let Some(max) = limits.max_repos {
+            if self.repos.len() > max {
+                exceeded.push(format!(
+                    "{} repositories changed, limit is {max}",
+                    self.repos.len()
+                ));
+            }
+        }
+        if exceeded.is_empty() {
+            Ok(())
+        } else {
+            Err(exceeded)
+        }
+    }
+}
+
+/// How to pick a single branch or tag when several match
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum BranchPick {
+    /// Most recent tip commit
+    Newest,
+    /// Oldest tip commit
+    Oldest,
+    /// Highest version found in the name, releases above pre-releases
+    SemverHighest,
+}
+
 /// Match struct specifying line where a search query was matched
 #[derive(Clone, Debug)]
 pub struct Match {
-    pub line: i16,
+    pub line: usize,
     pub content: String,
     pub replace: Option<String>,
+    /// Named groups captured by line pattern
+    pub captures: Vec<(String, String)>,
     pub page: Rc<Page>,
 }
 
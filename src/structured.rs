@@ -0,0 +1,524 @@
+use std::path::Path;
+
+use toml_edit::{DocumentMut, Item, Value};
+
+/// Structured file format recognized by extension
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Yaml,
+    Json,
+    Toml,
+}
+
+impl Format {
+    /// Get format from file extension
+    #[must_use]
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "yaml" | "yml" => Some(Self::Yaml),
+            "json" => Some(Self::Json),
+            "toml" => Some(Self::Toml),
+            _ => None,
+        }
+    }
+}
+
+/// Segment of a key path
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+/// Parse key path such as `kafka.bootstrap`, `$.spec.replicas` or `containers[0].image`.
+/// Numeric segments are treated as sequence indexes
+#[must_use]
+pub fn parse_key_path(path: &str) -> Vec<Segment> {
+    let path = path.strip_prefix('$').unwrap_or(path);
+    path.split('.')
+        .flat_map(|part| {
+            // Split `key[0][1]` into key and indexes
+            let mut parts = part.split('[');
+            let key = parts.next().unwrap_or_default();
+            let indexes = parts.filter_map(|i| i.trim_end_matches(']').parse().ok());
+            let key = match key.parse::<usize>() {
+                Ok(i) => Some(Segment::Index(i)),
+                Err(_) if key.is_empty() => None,
+                Err(_) => Some(Segment::Key(key.to_string())),
+            };
+            key.into_iter().chain(indexes.map(Segment::Index))
+        })
+        .collect()
+}
+
+/// Edit value at key path preserving formatting of the rest of the file.
+/// Returns line number and new contents of the edited line,
+/// or `None` if key path was not found
+pub fn edit(
+    format: Format,
+    contents: &str,
+    key_path: &[Segment],
+    value: &str,
+) -> Result<Option<(usize, String)>, String> {
+    match format {
+        Format::Yaml => edit_yaml(contents, key_path, value),
+        Format::Json => edit_json(contents, key_path, value),
+        Format::Toml => edit_toml(contents, key_path, value),
+    }
+}
+
+/// Edit value in block style YAML line by line.
+/// Only single line scalars are edited, block scalars, anchors, aliases
+/// and flow collections are refused
+fn edit_yaml(
+    contents: &str,
+    key_path: &[Segment],
+    value: &str,
+) -> Result<Option<(usize, String)>, String> {
+    // Stack of path segments with indentation they were found at
+    let mut stack: Vec<(usize, Segment)> = Vec::new();
+
+    for (n, line) in contents.lines().enumerate() {
+        let trimmed = line.trim_start();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        if trimmed.starts_with("---") {
+            stack.clear();
+            continue;
+        }
+        let mut indent = line.len() - trimmed.len();
+        let mut rest = trimmed;
+
+        // Sequence item, nested under a key at the same or lower indentation
+        if rest == "-" || rest.starts_with("- ") {
+            stack.retain(|(i, _)| *i <= indent);
+            let index = match stack.last() {
+                Some((i, Segment::Index(last))) if *i == indent => {
+                    let next = last + 1;
+                    stack.pop();
+                    next
+                }
+                _ => 0,
+            };
+            stack.push((indent, Segment::Index(index)));
+
+            let item = rest[1..].trim_start();
+            indent += rest.len() - item.len();
+            rest = item;
+            if rest.is_empty() {
+                continue;
+            }
+
+            // Scalar sequence item
+            if yaml_key(rest).is_none() {
+                if path_matches(&stack, key_path) {
+                    let start = line.len() - rest.len();
+                    check_yaml_scalar(rest, n)?;
+                    return Ok(Some((n, replace_yaml_scalar(line, start, value))));
+                }
+                continue;
+            }
+        } else {
+            stack.retain(|(i, _)| *i < indent);
+        }
+
+        let Some((key, value_start)) = yaml_key(rest) else {
+            continue;
+        };
+        stack.push((indent, Segment::Key(key)));
+
+        let start = line.len() - rest.len() + value_start;
+        let scalar = strip_yaml_comment(&line[start..]).trim();
+        if !scalar.is_empty() && path_matches(&stack, key_path) {
+            check_yaml_scalar(scalar, n)?;
+            return Ok(Some((n, replace_yaml_scalar(line, start, value))));
+        }
+    }
+    Ok(None)
+}
+
+/// Check that a value can be replaced on its line
+fn check_yaml_scalar(scalar: &str, line: usize) -> Result<(), String> {
+    let kind = match scalar.chars().next() {
+        Some('|' | '>') => "block scalar",
+        Some('&') => "anchor",
+        Some('*') => "alias",
+        Some('[' | '{') => "flow collection",
+        _ => return Ok(()),
+    };
+    Err(format!(
+        "Value at line {} is a {kind}, which is not edited",
+        line + 1
+    ))
+}
+
+/// Parse mapping key, returning key and byte offset of its value
+fn yaml_key(text: &str) -> Option<(String, usize)> {
+    let (key, after) = match text.chars().next()? {
+        quote @ ('"' | '\'') => {
+            let end = text[1..].find(quote)? + 1;
+            (text[1..end].to_string(), end + 1)
+        }
+        _ => {
+            let end = text
+                .find(": ")
+                .or_else(|| text.strip_suffix(':').map(str::len))?;
+            (text[..end].trim_end().to_string(), end)
+        }
+    };
+    let rest = text[after..].strip_prefix(':')?;
+    if !rest.is_empty() && !rest.starts_with(' ') {
+        return None;
+    }
+    let value_start = after + 1 + (rest.len() - rest.trim_start().len());
+    Some((key, value_start))
+}
+
+/// Strip trailing comment from a scalar, ignoring `#` inside quotes
+fn strip_yaml_comment(text: &str) -> &str {
+    let mut quote = None;
+    let mut prev = ' ';
+    for (i, c) in text.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), _) if c == q => quote = None,
+            (None, '#') if prev.is_whitespace() => return &text[..i],
+            _ => {}
+        }
+        prev = c;
+    }
+    text
+}
+
+/// Replace scalar starting at byte offset, keeping quote style and trailing comment
+fn replace_yaml_scalar(line: &str, start: usize, value: &str) -> String {
+    let scalar_text = strip_yaml_comment(&line[start..]);
+    let old = scalar_text.trim_end();
+    let suffix = &line[start + old.len()..];
+
+    let new = if old.starts_with('"') {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    } else if old.starts_with('\'') {
+        format!("'{}'", value.replace('\'', "''"))
+    } else if value.contains(": ") || value.contains(" #") || value.is_empty() {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        value.to_string()
+    };
+    format!("{}{new}{suffix}", &line[..start])
+}
+
+/// Check if segments on the stack form the key path
+fn path_matches(stack: &[(usize, Segment)], key_path: &[Segment]) -> bool {
+    stack.len() == key_path.len() && stack.iter().zip(key_path).all(|((_, a), b)| a == b)
+}
+
+/// Minimal JSON scanner locating the byte span of a value at a key path
+struct JsonScanner<'a> {
+    text: &'a [u8],
+    pos: usize,
+}
+
+impl JsonScanner<'_> {
+    fn skip_whitespace(&mut self) {
+        while self.text.get(self.pos).is_some_and(u8::is_ascii_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, c: u8) -> Result<(), String> {
+        self.skip_whitespace();
+        if self.text.get(self.pos) == Some(&c) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(format!("Expected '{}' at byte {}", c as char, self.pos))
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect(b'"')?;
+        let start = self.pos;
+        while let Some(c) = self.text.get(self.pos) {
+            match c {
+                b'\\' => self.pos += 2,
+                b'"' => {
+                    self.pos += 1;
+                    return Ok(String::from_utf8_lossy(&self.text[start..self.pos - 1]).into());
+                }
+                _ => self.pos += 1,
+            }
+        }
+        Err("Unterminated string".to_string())
+    }
+
+    /// Scan a value, returning span and whether it is a string
+    /// if its path equals the key path
+    fn value(
+        &mut self,
+        path: &mut Vec<Segment>,
+        key_path: &[Segment],
+    ) -> Result<Option<(usize, usize, bool)>, String> {
+        self.skip_whitespace();
+        let start = self.pos;
+        let mut found = None;
+
+        match self.text.get(self.pos) {
+            Some(b'{') => {
+                self.pos += 1;
+                self.skip_whitespace();
+                if self.text.get(self.pos) == Some(&b'}') {
+                    self.pos += 1;
+                    return Ok(None);
+                }
+                loop {
+                    let key = self.string()?;
+                    self.expect(b':')?;
+                    path.push(Segment::Key(key));
+                    found = found.or(self.value(path, key_path)?);
+                    path.pop();
+                    self.skip_whitespace();
+                    match self.text.get(self.pos) {
+                        Some(b',') => self.pos += 1,
+                        _ => break,
+                    }
+                }
+                self.expect(b'}')?;
+            }
+            Some(b'[') => {
+                self.pos += 1;
+                self.skip_whitespace();
+                if self.text.get(self.pos) == Some(&b']') {
+                    self.pos += 1;
+                    return Ok(None);
+                }
+                let mut index = 0;
+                loop {
+                    path.push(Segment::Index(index));
+                    found = found.or(self.value(path, key_path)?);
+                    path.pop();
+                    index += 1;
+                    self.skip_whitespace();
+                    match self.text.get(self.pos) {
+                        Some(b',') => self.pos += 1,
+                        _ => break,
+                    }
+                }
+                self.expect(b']')?;
+            }
+            Some(b'"') => {
+                self.string()?;
+                if path.as_slice() == key_path {
+                    found = Some((start, self.pos, true));
+                }
+            }
+            Some(_) => {
+                // Numbers, booleans and null
+                while self
+                    .text
+                    .get(self.pos)
+                    .is_some_and(|c| !matches!(c, b',' | b'}' | b']') && !c.is_ascii_whitespace())
+                {
+                    self.pos += 1;
+                }
+                if self.pos == start {
+                    return Err(format!("Unexpected character at byte {start}"));
+                }
+                if path.as_slice() == key_path {
+                    found = Some((start, self.pos, false));
+                }
+            }
+            None => return Err("Unexpected end of file".to_string()),
+        }
+        Ok(found)
+    }
+}
+
+/// Edit scalar value in JSON in place
+fn edit_json(
+    contents: &str,
+    key_path: &[Segment],
+    value: &str,
+) -> Result<Option<(usize, String)>, String> {
+    let mut scanner = JsonScanner {
+        text: contents.as_bytes(),
+        pos: 0,
+    };
+    let Some((start, end, is_string)) = scanner.value(&mut Vec::new(), key_path)? else {
+        return Ok(None);
+    };
+
+    // Keep strings as strings, other literals are written as is if valid
+    let literal = value == "true"
+        || value == "false"
+        || value == "null"
+        || value.parse::<f64>().is_ok_and(f64::is_finite);
+    let new = if is_string || !literal {
+        let escaped: String = value
+            .chars()
+            .map(|c| match c {
+                '"' => "\\\"".to_string(),
+                '\\' => "\\\\".to_string(),
+                '\n' => "\\n".to_string(),
+                '\t' => "\\t".to_string(),
+                c if c.is_control() => format!("\\u{:04x}", c as u32),
+                c => c.to_string(),
+            })
+            .collect();
+        format!("\"{escaped}\"")
+    } else {
+        value.to_string()
+    };
+
+    let line = contents[..start].matches('\n').count();
+    let line_start = contents[..start].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = contents[end..]
+        .find('\n')
+        .map_or(contents.len(), |i| end + i);
+    let new_line = format!(
+        "{}{new}{}",
+        &contents[line_start..start],
+        &contents[end..line_end]
+    );
+    Ok(Some((line, new_line.trim_end_matches('\r').to_string())))
+}
+
+/// Edit value in TOML using a format preserving document
+fn edit_toml(
+    contents: &str,
+    key_path: &[Segment],
+    value: &str,
+) -> Result<Option<(usize, String)>, String> {
+    let mut doc: DocumentMut = contents.parse().map_err(|e| format!("{e}"))?;
+
+    let mut item: &mut Item = doc.as_item_mut();
+    for segment in key_path {
+        let next = match segment {
+            Segment::Key(key) => item.get_mut(key.as_str()),
+            Segment::Index(index) => item.get_mut(*index),
+        };
+        match next {
+            Some(next) => item = next,
+            None => return Ok(None),
+        }
+    }
+    let Some(old) = item.as_value_mut() else {
+        return Ok(None);
+    };
+
+    // Keep value type and surrounding whitespace and comments
+    let mut new = match old {
+        Value::Integer(_) => value
+            .parse::<i64>()
+            .map_or_else(|_| value.into(), Value::from),
+        Value::Float(_) => value
+            .parse::<f64>()
+            .map_or_else(|_| value.into(), Value::from),
+        Value::Boolean(_) => value
+            .parse::<bool>()
+            .map_or_else(|_| value.into(), Value::from),
+        _ => value.into(),
+    };
+    *new.decor_mut() = old.decor().clone();
+    *old = new;
+
+    // Find the edited line
+    let edited = doc.to_string();
+    Ok(contents
+        .lines()
+        .zip(edited.lines())
+        .enumerate()
+        .find(|(_, (a, b))| a != b)
+        .map(|(n, (_, b))| (n, b.to_string())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_path_parse() {
+        assert_eq!(
+            parse_key_path("$.spec.containers[1].image"),
+            vec![
+                Segment::Key("spec".to_string()),
+                Segment::Key("containers".to_string()),
+                Segment::Index(1),
+                Segment::Key("image".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn yaml_edit_nested() {
+        let yaml = "kafka:\n  # bootstrap server\n  bootstrap: \"prod-kafka:9092\" # prod\n  \
+                    topic: events\nbootstrap: other\n";
+        let res = edit(
+            Format::Yaml,
+            yaml,
+            &parse_key_path("kafka.bootstrap"),
+            "dev-kafka:9092",
+        );
+        assert_eq!(
+            res.unwrap(),
+            Some((2, "  bootstrap: \"dev-kafka:9092\" # prod".to_string()))
+        );
+    }
+
+    #[test]
+    fn yaml_edit_sequence() {
+        let yaml =
+            "spec:\n  containers:\n  - name: a\n    image: a:1\n  - name: b\n    image: b:1\n";
+        let res = edit(
+            Format::Yaml,
+            yaml,
+            &parse_key_path("spec.containers[1].image"),
+            "b:2",
+        );
+        assert_eq!(res.unwrap(), Some((5, "    image: b:2".to_string())));
+    }
+
+    #[test]
+    fn yaml_edit_refused() {
+        let key_path = parse_key_path("kafka.config");
+        for value in [
+            "|\n    a: 1\n    b: 2",
+            ">-\n    folded",
+            "&base prod",
+            "*base",
+            "[a, b]",
+            "{a: 1}",
+        ] {
+            let yaml = format!("kafka:\n  config: {value}\n  topic: events\n");
+            let res = edit(Format::Yaml, &yaml, &key_path, "new");
+            assert!(res.is_err(), "{value}");
+        }
+
+        let yaml = "hosts:\n- [a, b]\n- c\n";
+        assert!(edit(Format::Yaml, yaml, &parse_key_path("hosts[0]"), "new").is_err());
+        let res = edit(Format::Yaml, yaml, &parse_key_path("hosts[1]"), "new");
+        assert_eq!(res.unwrap(), Some((2, "- new".to_string())));
+    }
+
+    #[test]
+    fn json_edit() {
+        let json = "{\n  \"spec\": {\n    \"replicas\": 1,\n    \"name\": \"a\"\n  }\n}\n";
+        let res = edit(Format::Json, json, &parse_key_path("$.spec.replicas"), "3");
+        assert_eq!(res.unwrap(), Some((2, "    \"replicas\": 3,".to_string())));
+        let res = edit(Format::Json, json, &parse_key_path("spec.name"), "b");
+        assert_eq!(res.unwrap(), Some((3, "    \"name\": \"b\"".to_string())));
+    }
+
+    #[test]
+    fn toml_edit() {
+        let toml = "[package]\nname = \"a\"  # name\nversion = \"0.1.0\"\n";
+        let res = edit(
+            Format::Toml,
+            toml,
+            &parse_key_path("package.version"),
+            "0.2.0",
+        );
+        assert_eq!(res.unwrap(), Some((2, "version = \"0.2.0\"".to_string())));
+    }
+}
@@ -0,0 +1,147 @@
+use regex::Captures;
+
+/// Placeholders available in replacement templates
+#[derive(Clone, Debug, Default)]
+pub struct Context {
+    pub repo: String,
+    pub branch: String,
+    pub file: String,
+}
+
+/// Expand a replacement template.
+///
+/// `$1`, `${1}`, `$name` and `${name}` refer to groups captured by the select pattern,
+/// falling back to named groups captured by the line pattern
+/// and `${repo}`, `${branch}` and `${file}` placeholders.
+/// `${name:upper}`, `${name:lower}` and `${name:title}` transform case
+/// and `$$` is a literal `$`
+#[must_use]
+pub fn expand(
+    template: &str,
+    caps: &Captures,
+    line_caps: &[(String, String)],
+    context: &Context,
+) -> String {
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(i) = rest.find('$') {
+        result.push_str(&rest[..i]);
+        rest = &rest[i + 1..];
+
+        // Parse reference after `$`
+        let (reference, after) = if let Some(escaped) = rest.strip_prefix('$') {
+            result.push('$');
+            rest = escaped;
+            continue;
+        } else if let Some(braced) = rest.strip_prefix('{') {
+            match braced.find('}') {
+                Some(end) => (&braced[..end], &braced[end + 1..]),
+                None => {
+                    result.push('$');
+                    continue;
+                }
+            }
+        } else {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            (&rest[..end], &rest[end..])
+        };
+        rest = after;
+
+        if reference.is_empty() {
+            result.push('$');
+            continue;
+        }
+
+        let (name, transform) = reference
+            .split_once(':')
+            .map_or((reference, None), |(n, t)| (n, Some(t)));
+        let value = lookup(name, caps, line_caps, context).unwrap_or_default();
+        result.push_str(&transform_case(&value, transform));
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Look up value of a capture group or placeholder
+fn lookup(
+    name: &str,
+    caps: &Captures,
+    line_caps: &[(String, String)],
+    context: &Context,
+) -> Option<String> {
+    let group = match name.parse::<usize>() {
+        Ok(n) => caps.get(n),
+        Err(_) => caps.name(name),
+    };
+    group
+        .map(|m| m.as_str().to_string())
+        .or_else(|| {
+            line_caps
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, v)| v.clone())
+        })
+        .or_else(|| match name {
+            "repo" => Some(context.repo.clone()),
+            "branch" => Some(context.branch.clone()),
+            "file" => Some(context.file.clone()),
+            _ => None,
+        })
+}
+
+/// Apply case transform to a value
+fn transform_case(value: &str, transform: Option<&str>) -> String {
+    match transform {
+        Some("upper") => value.to_uppercase(),
+        Some("lower") => value.to_lowercase(),
+        Some("title") => {
+            let mut chars = value.chars();
+            chars.next().map_or_else(String::new, |first| {
+                first
+                    .to_uppercase()
+                    .chain(chars.flat_map(char::to_lowercase))
+                    .collect()
+            })
+        }
+        _ => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex::Regex;
+
+    fn context() -> Context {
+        Context {
+            repo: "org/svc".to_string(),
+            branch: "main".to_string(),
+            file: "deploy/values.yaml".to_string(),
+        }
+    }
+
+    #[test]
+    fn template_groups() {
+        let re = Regex::new(r"(?P<svc>\w+):(\d+)").unwrap();
+        let caps = re.captures("image: api:12").unwrap();
+        let res = expand("${svc:upper}-$2 ${svc:title}", &caps, &[], &context());
+        assert_eq!(res, "API-12 Api");
+    }
+
+    #[test]
+    fn template_line_groups_and_placeholders() {
+        let re = Regex::new(r"\d+").unwrap();
+        let caps = re.captures("tag: 12").unwrap();
+        let line_caps = vec![("svc".to_string(), "api".to_string())];
+        let res = expand(
+            "$svc@${branch} in ${repo} costs $$5",
+            &caps,
+            &line_caps,
+            &context(),
+        );
+        assert_eq!(res, "api@main in org/svc costs $5");
+    }
+}
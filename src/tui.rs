@@ -0,0 +1,470 @@
+use std::fs;
+
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Borders, List, ListItem, ListState, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+
+use crate::raider::RepoRaider;
+use crate::structs::{Directory, Page};
+
+/// Action chosen when leaving the browser
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Action {
+    Quit,
+    Apply,
+    Commit,
+    Push,
+}
+
+/// Number of lines shown before and after a match in preview
+const CONTEXT_LINES: usize = 3;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Level {
+    Repos,
+    Files,
+    Lines,
+}
+
+/// Selection of a page and each of its matches
+struct Selection {
+    page: bool,
+    matches: Vec<bool>,
+}
+
+struct Browser<'a> {
+    raider: &'a RepoRaider,
+    /// Indices of directories with matches or changes
+    dirs: Vec<usize>,
+    /// Selection per directory and page index
+    selection: Vec<Vec<Selection>>,
+    level: Level,
+    repo: ListState,
+    file: ListState,
+    line: ListState,
+    /// Lines of currently open file
+    lines: Vec<String>,
+    /// Whether commit and push actions are available
+    can_commit: bool,
+    can_push: bool,
+}
+
+/// Browse matched repositories, files and lines in a terminal UI.
+/// Commit needs a commit message and push also a username, otherwise their keys are disabled.
+/// Deselected matches are dropped so that apply only writes selected ones
+pub fn browse(raider: &mut RepoRaider, can_commit: bool, can_push: bool) -> Action {
+    let mut terminal = ratatui::init();
+    let (action, selection) = {
+        let mut browser = Browser::new(raider);
+        browser.can_commit = can_commit;
+        browser.can_push = can_commit && can_push;
+        let action = browser.run(&mut terminal);
+        (action, browser.selection)
+    };
+    ratatui::restore();
+
+    // Drop changes that were deselected
+    for (dir, dir_selection) in raider.dirs.iter_mut().zip(selection) {
+        for (page, selection) in dir.pages.iter_mut().zip(dir_selection) {
+            if !selection.page {
+                page.discard_changes();
+                continue;
+            }
+            page.matches
+                .iter_mut()
+                .zip(selection.matches)
+                .filter(|(_, on)| !on)
+                .for_each(|(m, _)| m.replace = None);
+            if page.content.is_none() && page.matches.iter().all(|m| m.replace.is_none()) {
+                page.changes = false;
+            }
+        }
+    }
+    action
+}
+
+/// Check if page is shown in browser
+fn is_listed(page: &Page) -> bool {
+    !page.matches.is_empty() || page.changes
+}
+
+/// Check if match proposes a change that can be toggled
+fn is_change(page: &Page, index: usize) -> bool {
+    let m = &page.matches[index];
+    page.changes && m.replace.as_ref().is_some_and(|r| *r != m.content)
+}
+
+/// Render checkbox
+const fn checkbox(on: bool) -> &'static str {
+    if on {
+        "[x]"
+    } else {
+        "[ ]"
+    }
+}
+
+impl<'a> Browser<'a> {
+    fn new(raider: &'a RepoRaider) -> Self {
+        let dirs: Vec<usize> = raider
+            .dirs
+            .iter()
+            .enumerate()
+            .filter(|(_, d)| d.pages.iter().any(is_listed))
+            .map(|(i, _)| i)
+            .collect();
+        let selection = raider
+            .dirs
+            .iter()
+            .map(|d| {
+                d.pages
+                    .iter()
+                    .map(|p| Selection {
+                        page: true,
+                        matches: vec![true; p.matches.len()],
+                    })
+                    .collect()
+            })
+            .collect();
+        let mut repo = ListState::default();
+        if !dirs.is_empty() {
+            repo.select(Some(0));
+        }
+
+        Self {
+            raider,
+            dirs,
+            selection,
+            level: Level::Repos,
+            repo,
+            file: ListState::default(),
+            line: ListState::default(),
+            lines: Vec::new(),
+            can_commit: false,
+            can_push: false,
+        }
+    }
+
+    /// Handle key presses until an action is chosen
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> Action {
+        loop {
+            terminal
+                .draw(|frame| self.draw(frame))
+                .expect("Error drawing terminal");
+            let Event::Key(key) = event::read().expect("Error reading terminal event") else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            match key.code {
+                KeyCode::Char('q') => return Action::Quit,
+                KeyCode::Char('a') => return Action::Apply,
+                KeyCode::Char('c') if self.can_commit => return Action::Commit,
+                KeyCode::Char('p') if self.can_push => return Action::Push,
+                KeyCode::Up | KeyCode::Char('k') => self.step(-1),
+                KeyCode::Down | KeyCode::Char('j') => self.step(1),
+                KeyCode::Enter | KeyCode::Right | KeyCode::Char('l') => self.enter(),
+                KeyCode::Esc | KeyCode::Backspace | KeyCode::Left | KeyCode::Char('h') => {
+                    self.back();
+                }
+                KeyCode::Char(' ') => self.toggle(),
+                _ => {}
+            }
+        }
+    }
+
+    fn dir(&self) -> Option<(usize, &'a Directory)> {
+        let index = self.dirs.get(self.repo.selected()?)?;
+        Some((*index, &self.raider.dirs[*index]))
+    }
+
+    /// Indices of listed pages of current directory
+    fn pages(&self) -> Vec<usize> {
+        self.dir().map_or_else(Vec::new, |(_, dir)| {
+            dir.pages
+                .iter()
+                .enumerate()
+                .filter(|(_, p)| is_listed(p))
+                .map(|(i, _)| i)
+                .collect()
+        })
+    }
+
+    fn page(&self) -> Option<(usize, &'a Page)> {
+        let (_, dir) = self.dir()?;
+        let index = *self.pages().get(self.file.selected()?)?;
+        Some((index, &dir.pages[index]))
+    }
+
+    fn state(&mut self) -> (&mut ListState, usize) {
+        match self.level {
+            Level::Repos => (&mut self.repo, self.dirs.len()),
+            Level::Files => {
+                let len = self.pages().len();
+                (&mut self.file, len)
+            }
+            Level::Lines => {
+                let len = self.page().map_or(0, |(_, p)| p.matches.len());
+                (&mut self.line, len)
+            }
+        }
+    }
+
+    /// Move cursor by delta within current list
+    fn step(&mut self, delta: isize) {
+        let (state, len) = self.state();
+        if len == 0 {
+            return;
+        }
+        let current = state.selected().unwrap_or(0);
+        state.select(Some(current.saturating_add_signed(delta).min(len - 1)));
+    }
+
+    fn enter(&mut self) {
+        match self.level {
+            Level::Repos if self.dir().is_some() => {
+                self.level = Level::Files;
+                self.file.select(Some(0));
+            }
+            Level::Files => {
+                if let Some((_, page)) = self.page() {
+                    self.lines = fs::read_to_string(&page.path)
+                        .unwrap_or_default()
+                        .lines()
+                        .map(str::to_string)
+                        .collect();
+                    self.level = Level::Lines;
+                    self.line.select((!page.matches.is_empty()).then_some(0));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn back(&mut self) {
+        self.level = match self.level {
+            Level::Repos | Level::Files => Level::Repos,
+            Level::Lines => Level::Files,
+        };
+    }
+
+    /// Toggle selection under cursor, including all matches below it
+    fn toggle(&mut self) {
+        let Some((dir_index, _)) = self.dir() else {
+            return;
+        };
+        match self.level {
+            Level::Repos => {
+                let on = !self.is_dir_on(dir_index);
+                for page in self.pages() {
+                    self.set_page(dir_index, page, on);
+                }
+            }
+            Level::Files => {
+                if let Some((page_index, _)) = self.page() {
+                    let on = !self.is_page_on(dir_index, page_index);
+                    self.set_page(dir_index, page_index, on);
+                }
+            }
+            Level::Lines => {
+                let Some((page_index, page)) = self.page() else {
+                    return;
+                };
+                let Some(line) = self.line.selected() else {
+                    return;
+                };
+                if is_change(page, line) {
+                    let selection = &mut self.selection[dir_index][page_index];
+                    selection.matches[line] = !selection.matches[line];
+                    selection.page =
+                        selection.matches.iter().any(|on| *on) || page.content.is_some();
+                }
+            }
+        }
+    }
+
+    fn set_page(&mut self, dir: usize, page: usize, on: bool) {
+        let selection = &mut self.selection[dir][page];
+        selection.page = on;
+        selection.matches.iter_mut().for_each(|m| *m = on);
+    }
+
+    fn is_page_on(&self, dir: usize, page: usize) -> bool {
+        self.selection[dir][page].page
+    }
+
+    fn is_dir_on(&self, dir: usize) -> bool {
+        self.pages().iter().any(|&p| self.is_page_on(dir, p))
+    }
+
+    /// Count selected changes of a page
+    fn selected_changes(&self, dir: usize, page: usize) -> usize {
+        let p = &self.raider.dirs[dir].pages[page];
+        let selection = &self.selection[dir][page];
+        if !selection.page {
+            return 0;
+        }
+        (0..p.matches.len())
+            .filter(|&i| selection.matches[i] && is_change(p, i))
+            .count()
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [list_area, preview_area, help_area] = Layout::vertical([
+            Constraint::Percentage(60),
+            Constraint::Min(3),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        let (title, items, preview) = match self.level {
+            Level::Repos => self.repos_view(),
+            Level::Files => self.files_view(),
+            Level::Lines => self.lines_view(),
+        };
+        let list = List::new(items)
+            .block(Block::default().borders(Borders::ALL).title(title))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        let state = match self.level {
+            Level::Repos => &mut self.repo,
+            Level::Files => &mut self.file,
+            Level::Lines => &mut self.line,
+        };
+        frame.render_stateful_widget(list, list_area, state);
+        frame.render_widget(
+            Paragraph::new(preview).block(Block::default().borders(Borders::ALL).title("Preview")),
+            preview_area,
+        );
+        let mut help = String::from("↑↓ move  enter open  esc back  space toggle  a apply");
+        if self.can_commit {
+            help.push_str("  c commit");
+        }
+        if self.can_push {
+            help.push_str("  p push");
+        }
+        help.push_str("  q quit");
+        frame.render_widget(Paragraph::new(help), help_area);
+    }
+
+    fn repos_view(&self) -> (String, Vec<ListItem<'a>>, Vec<Line<'a>>) {
+        let items = self
+            .dirs
+            .iter()
+            .map(|&d| {
+                let dir = &self.raider.dirs[d];
+                let files = dir.pages.iter().filter(|p| is_listed(p)).count();
+                let matches: usize = dir.pages.iter().map(|p| p.matches.len()).sum();
+                let changes: usize = (0..dir.pages.len())
+                    .map(|p| self.selected_changes(d, p))
+                    .sum();
+                let on = dir
+                    .pages
+                    .iter()
+                    .enumerate()
+                    .any(|(p, page)| is_listed(page) && self.is_page_on(d, p));
+                ListItem::new(format!(
+                    "{} {}  {files} files, {matches} matches, {changes} selected changes",
+                    checkbox(on),
+                    dir.relative_path.display()
+                ))
+            })
+            .collect();
+        let preview = self.dir().map_or_else(Vec::new, |(_, dir)| {
+            dir.pages
+                .iter()
+                .filter(|p| is_listed(p))
+                .map(|p| {
+                    Line::from(format!(
+                        "F{}: {}",
+                        p.matches.len(),
+                        p.relative_path.display()
+                    ))
+                })
+                .collect()
+        });
+        ("Repositories".to_string(), items, preview)
+    }
+
+    fn files_view(&self) -> (String, Vec<ListItem<'a>>, Vec<Line<'a>>) {
+        let Some((d, dir)) = self.dir() else {
+            return (String::new(), Vec::new(), Vec::new());
+        };
+        let items = self
+            .pages()
+            .into_iter()
+            .map(|p| {
+                let page = &dir.pages[p];
+                ListItem::new(format!(
+                    "{} {}  {} matches, {} selected changes",
+                    checkbox(self.is_page_on(d, p)),
+                    page.relative_path.display(),
+                    page.matches.len(),
+                    self.selected_changes(d, p)
+                ))
+            })
+            .collect();
+        let preview = self.page().map_or_else(Vec::new, |(_, page)| {
+            let mut lines: Vec<Line> = page
+                .matches
+                .iter()
+                .flat_map(|m| {
+                    let mut lines = vec![Line::from(format!("L{}: {}", m.line, m.content))];
+                    if let Some(r) = &m.replace {
+                        lines.push(Line::from(format!("R{}: {r}", m.line)));
+                    }
+                    lines
+                })
+                .collect();
+            if page.changes && page.matches.iter().all(|m| m.replace.is_none()) {
+                lines.push(Line::from("Changed by command"));
+            }
+            lines
+        });
+        (dir.relative_path.display().to_string(), items, preview)
+    }
+
+    fn lines_view(&self) -> (String, Vec<ListItem<'a>>, Vec<Line<'a>>) {
+        let (Some((d, _)), Some((p, page))) = (self.dir(), self.page()) else {
+            return (String::new(), Vec::new(), Vec::new());
+        };
+        let selection = &self.selection[d][p];
+        let items = page
+            .matches
+            .iter()
+            .enumerate()
+            .map(|(i, m)| {
+                let mark = if is_change(page, i) {
+                    checkbox(selection.page && selection.matches[i])
+                } else {
+                    "   "
+                };
+                ListItem::new(format!("{mark} L{}: {}", m.line, m.content))
+            })
+            .collect();
+
+        // Show match with surrounding lines and its replacement
+        let preview = self.line.selected().map_or_else(Vec::new, |i| {
+            let m = &page.matches[i];
+            let line = m.line;
+            let start = line.saturating_sub(CONTEXT_LINES);
+            let end = (line + CONTEXT_LINES + 1).min(self.lines.len());
+            let mut preview = Vec::new();
+            for (n, content) in self.lines.iter().enumerate().take(end).skip(start) {
+                if n == line && m.replace.is_some() {
+                    preview.push(Line::from(format!("-{n:>5}: {}", m.content)));
+                    preview.push(Line::from(format!(
+                        "+{n:>5}: {}",
+                        m.replace.as_deref().unwrap_or_default()
+                    )));
+                } else {
+                    preview.push(Line::from(format!(" {n:>5}: {content}")));
+                }
+            }
+            preview
+        });
+        (page.relative_path.display().to_string(), items, preview)
+    }
+}